@@ -1,13 +1,18 @@
 use tauri::{Manager, Emitter, menu::{MenuBuilder, MenuItemBuilder}};
-use tauri_plugin_window_state;
+use tauri::tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent};
 use serde::{Deserialize, Serialize};
+use tauri_plugin_store::StoreExt;
 use tokio_util::sync::CancellationToken;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use async_stream::stream;
 use actix_web::{web, App, HttpServer, HttpResponse, Error};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
 use actix_cors::Cors;
-use uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
@@ -24,6 +29,32 @@ pub struct ChatRequest {
     pub stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub options: Option<serde_json::Value>,
+    /// Provider-agnostic sampling settings, translated to each backend's key
+    /// names when the body is built. Same-named keys in `options` win.
+    #[serde(default, skip_serializing)]
+    pub max_tokens: Option<u64>,
+    #[serde(default, skip_serializing)]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing)]
+    pub top_p: Option<f64>,
+    /// How long Ollama keeps the model loaded ("5m", "-1", "0"). Ignored by
+    /// other providers.
+    #[serde(default, skip_serializing)]
+    pub keep_alive: Option<String>,
+    /// Selects the upstream adapter ("openai", "ollama", "anthropic",
+    /// "gemini"). When absent the provider is inferred from the endpoint URL.
+    #[serde(default, skip_serializing)]
+    pub provider: Option<String>,
+    /// Extra headers sent upstream, e.g. OpenRouter's `HTTP-Referer` and `X-Title`.
+    #[serde(default, skip_serializing)]
+    pub headers: Option<HashMap<String, String>>,
+    /// Replaces the default `User-Agent` for this request.
+    #[serde(default, skip_serializing)]
+    pub user_agent: Option<String>,
+    /// Lets a `Content-Type` in `headers` replace `application/json`, for
+    /// gateways that expect a vendor type. Otherwise it is ignored.
+    #[serde(default, skip_serializing)]
+    pub override_content_type: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,6 +63,155 @@ struct ChatResponse {
     choices: Option<Vec<Choice>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     message: Option<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+    /// Ollama's native equivalent of `finish_reason`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    done_reason: Option<String>,
+}
+
+impl ChatResponse {
+    fn role(&self) -> Option<&str> {
+        self.choices
+            .as_ref()
+            .and_then(|choices| choices.first())
+            .and_then(|choice| choice.delta.as_ref())
+            .and_then(|d| d.role.as_deref())
+    }
+
+    fn finish_reason(&self) -> Option<String> {
+        self.choices
+            .as_ref()
+            .and_then(|choices| choices.first())
+            .and_then(|choice| choice.finish_reason.as_deref())
+            .or(self.done_reason.as_deref())
+            .map(normalize_finish_reason)
+    }
+}
+
+/// Maps provider-specific stop reasons onto OpenAI's vocabulary, so callers
+/// only need to check for e.g. "length" to offer a "continue" action.
+fn normalize_finish_reason(reason: &str) -> String {
+    match reason.to_ascii_lowercase().as_str() {
+        "max_tokens" | "length" => "length".to_string(),
+        "end_turn" | "stop_sequence" | "stop" => "stop".to_string(),
+        "tool_use" | "tool_calls" => "tool_calls".to_string(),
+        "safety" | "recitation" | "blocklist" | "prohibited_content" | "content_filter" => {
+            "content_filter".to_string()
+        }
+        other => other.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatResult {
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// "stop", "length", "content_filter", ... when the upstream reported it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Rate-limit headers from the upstream response, keyed in lowercase.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+}
+
+/// A complete tool call, with `arguments` as the JSON text the model produced.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolCall {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", default = "default_tool_type")]
+    pub kind: String,
+    pub function: ToolFunction,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolFunction {
+    pub name: String,
+    pub arguments: String,
+}
+
+fn default_tool_type() -> String {
+    "function".to_string()
+}
+
+/// A tool call as it appears in a message or stream delta. OpenAI streams
+/// split one call across many deltas sharing an `index`; Ollama sends whole
+/// calls with `arguments` as an object.
+#[derive(Debug, Serialize, Deserialize)]
+struct ToolCallDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function: Option<ToolFunctionDelta>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ToolFunctionDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arguments: Option<serde_json::Value>,
+}
+
+/// Reassembles tool calls from stream fragments, keyed by their `index`.
+#[derive(Debug, Default)]
+struct ToolCallAccumulator {
+    calls: Vec<(Option<usize>, ToolCall)>,
+}
+
+impl ToolCallAccumulator {
+    fn push(&mut self, deltas: &[ToolCallDelta]) {
+        for delta in deltas {
+            // Fragments without an index are whole calls of their own
+            let pos = delta
+                .index
+                .and_then(|index| self.calls.iter().position(|(i, _)| *i == Some(index)));
+            let pos = pos.unwrap_or_else(|| {
+                self.calls.push((delta.index, ToolCall { kind: default_tool_type(), ..Default::default() }));
+                self.calls.len() - 1
+            });
+            let call = &mut self.calls[pos].1;
+
+            if let Some(id) = &delta.id {
+                call.id = Some(id.clone());
+            }
+            if let Some(kind) = &delta.kind {
+                call.kind = kind.clone();
+            }
+            if let Some(function) = &delta.function {
+                if let Some(name) = &function.name {
+                    call.function.name.push_str(name);
+                }
+                match &function.arguments {
+                    Some(serde_json::Value::String(fragment)) => call.function.arguments.push_str(fragment),
+                    Some(serde_json::Value::Null) | None => {}
+                    Some(value) => call.function.arguments.push_str(&value.to_string()),
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> Vec<ToolCall> {
+        self.calls.into_iter().map(|(_, call)| call).collect()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,507 +220,7330 @@ struct Choice {
     delta: Option<Delta>,
     #[serde(skip_serializing_if = "Option::is_none")]
     message: Option<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", alias = "reasoning")]
+    reasoning_content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Message {
     #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct StreamError {
-    pub error: String,
+#[derive(Debug, Deserialize)]
+struct AnthropicResponse {
+    #[serde(default)]
+    content: Vec<AnthropicContentBlock>,
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
+    #[serde(default)]
+    stop_reason: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaModel {
-    name: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    modified_at: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    size: Option<u64>,
+#[derive(Debug, Deserialize)]
+struct AnthropicContentBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: Option<String>,
+    /// The remaining fields are only set on `tool_use` blocks.
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    input: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaTagsResponse {
-    models: Vec<OllamaModel>,
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
 }
 
-#[derive(Debug, Clone)]
-struct StreamContext {
-    token: CancellationToken,
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+    #[serde(default)]
+    candidates: Vec<GeminiCandidate>,
+    #[serde(default)]
+    usage_metadata: Option<GeminiUsage>,
 }
 
-#[derive(Deserialize)]
-struct ProxyChatRequest {
-    api_endpoint: String,
-    api_key: Option<String>,
-    request: ChatRequest,
+#[derive(Debug, Deserialize)]
+struct GeminiCandidate {
+    #[serde(default)]
+    content: Option<GeminiContent>,
+    #[serde(default, rename = "finishReason")]
+    finish_reason: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct ProxyModelsRequest {
-    api_endpoint: String,
-    api_key: Option<String>,
+#[derive(Debug, Deserialize)]
+struct GeminiContent {
+    #[serde(default)]
+    parts: Vec<GeminiPart>,
 }
 
-type TokenMap = Arc<Mutex<HashMap<String, StreamContext>>>;
-
-pub struct ProxyState {
-    pub server_handle: Mutex<Option<actix_web::dev::ServerHandle>>,
+#[derive(Debug, Deserialize)]
+struct GeminiPart {
+    #[serde(default)]
+    text: Option<String>,
+    /// Set on thinking-model summaries.
+    #[serde(default)]
+    thought: bool,
 }
 
-async fn handle_chat(req: web::Json<ProxyChatRequest>) -> Result<HttpResponse, Error> {
-    match proxy_chat_request(req.api_endpoint.clone(), req.api_key.clone(), req.request.clone()).await {
-        Ok(content) => Ok(HttpResponse::Ok().json(serde_json::json!({"content": content}))),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({"error": e}))),
-    }
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiUsage {
+    #[serde(default)]
+    prompt_token_count: u64,
+    #[serde(default)]
+    candidates_token_count: u64,
+    #[serde(default)]
+    total_token_count: u64,
 }
 
-async fn handle_chat_stream(req: web::Json<ProxyChatRequest>) -> Result<HttpResponse, Error> {
-    use futures::StreamExt;
-    use bytes::Bytes;
+impl GeminiResponse {
+    /// Concatenated (reasoning, answer) text of the first candidate.
+    fn text(&self) -> (String, String) {
+        let mut reasoning = String::new();
+        let mut content = String::new();
 
-    match proxy_chat_stream(req.api_endpoint.clone(), req.api_key.clone(), req.request.clone()).await {
-        Ok(stream) => {
-            let mapped_stream = stream.map(|res| {
-                match res {
-                    Ok(s) => Ok(Bytes::from(s)),
-                    Err(e) => Err(actix_web::error::ErrorInternalServerError(e)),
-                }
-            });
+        let parts = self
+            .candidates
+            .first()
+            .and_then(|c| c.content.as_ref())
+            .map(|c| c.parts.as_slice())
+            .unwrap_or_default();
 
-            Ok(HttpResponse::Ok()
-                .content_type("text/event-stream")
-                .streaming(mapped_stream))
+        for part in parts {
+            if let Some(text) = &part.text {
+                if part.thought {
+                    reasoning.push_str(text);
+                } else {
+                    content.push_str(text);
+                }
+            }
         }
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({"error": e}))),
+
+        (reasoning, content)
+    }
+
+    fn finish_reason(&self) -> Option<String> {
+        self.candidates
+            .first()
+            .and_then(|c| c.finish_reason.as_deref())
+            .filter(|r| *r != "FINISH_REASON_UNSPECIFIED")
+            .map(normalize_finish_reason)
     }
-}
 
-async fn handle_models(req: web::Json<ProxyModelsRequest>) -> Result<HttpResponse, Error> {
-    match proxy_scan_ollama_models(req.api_endpoint.clone(), req.api_key.clone()).await {
-        Ok(models) => Ok(HttpResponse::Ok().json(serde_json::json!({"models": models}))),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({"error": e}))),
+    fn usage(&self) -> Option<Usage> {
+        self.usage_metadata.as_ref().map(|u| Usage {
+            prompt_tokens: u.prompt_token_count,
+            completion_tokens: u.candidates_token_count,
+            total_tokens: u.total_token_count,
+        })
     }
 }
 
-fn build_http_client(timeout_secs: u64) -> Result<reqwest::Client, String> {
-    reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(timeout_secs))
-        .build()
-        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamError {
+    pub error: String,
 }
 
-fn add_auth_headers(builder: reqwest::RequestBuilder, api_key: Option<String>) -> reqwest::RequestBuilder {
-    if let Some(key) = api_key {
-        builder.header("Authorization", format!("Bearer {}", key))
-    } else {
-        builder
-    }
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamHeartbeat {
+    pub elapsed_ms: u64,
+    pub total_bytes: usize,
+    pub paused: bool,
 }
 
-async fn proxy_chat_request(
-    api_endpoint: String,
-    api_key: Option<String>,
-    request: ChatRequest,
-) -> Result<String, String> {
-    let client = build_http_client(300)?;
+/// Final event of a `send_chat_stream` call.
+///
+/// After a cancel (or idle timeout) `cancelled` is set and `content` holds
+/// the partial answer. To continue from there, resend the same messages
+/// followed by `{"role": "assistant", "content": <content>}` and, for models
+/// that don't resume a trailing assistant turn on their own, a user message
+/// such as "Continue exactly where you left off."
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamDone {
+    pub cancelled: bool,
+    pub total_bytes: usize,
+    /// Role from the first delta that carried one.
+    pub role: Option<String>,
+    pub finish_reason: Option<String>,
+    /// The full answer text, for callers that don't want to rebuild it from
+    /// the individual chunk events. With `repair_partial_markdown`, a cut-off
+    /// answer has its open code fence closed, so strip that before resending.
+    pub content: String,
+    /// `content` stopped growing at `max_content_bytes`; the chunk events
+    /// still carried the whole answer.
+    pub truncated: bool,
+    /// The upstream went quiet for `idle_timeout_secs` and the stream was
+    /// abandoned; unlike `cancelled`, nobody asked for it to stop.
+    pub timed_out: bool,
+}
 
-    let mut req_builder = client
-        .post(&api_endpoint)
-        .header("Content-Type", "application/json");
+/// Answer text currently held by in-flight `send_chat_stream` calls.
+#[derive(Default)]
+struct StreamMemory {
+    streams: AtomicUsize,
+    bytes: AtomicUsize,
+}
 
-    req_builder = add_auth_headers(req_builder, api_key);
+/// One stream's share of `StreamMemory`, released when the stream ends.
+struct StreamMemoryGuard<'a> {
+    memory: &'a StreamMemory,
+    bytes: usize,
+}
 
-    let response = req_builder
-        .json(&request)
-        .send()
-        .await
-        .map_err(|e| format!("Request failed: {}", e))?;
+impl<'a> StreamMemoryGuard<'a> {
+    fn new(memory: &'a StreamMemory) -> Self {
+        memory.streams.fetch_add(1, Ordering::Relaxed);
+        Self { memory, bytes: 0 }
+    }
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Failed to read error response".to_string());
-        return Err(format!("API error: {} - {}", status, error_text));
+    fn grow(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        self.memory.bytes.fetch_add(bytes, Ordering::Relaxed);
     }
+}
 
-    let response_data: ChatResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+impl Drop for StreamMemoryGuard<'_> {
+    fn drop(&mut self) {
+        self.memory.streams.fetch_sub(1, Ordering::Relaxed);
+        self.memory.bytes.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
 
-    let content = if let Some(choices) = response_data.choices {
-        choices
-            .first()
-            .and_then(|choice| choice.message.as_ref())
-            .and_then(|m| m.content.as_ref())
-            .cloned()
-            .unwrap_or_default()
-    } else if let Some(msg) = response_data.message {
-        msg.content.unwrap_or_default()
-    } else {
-        String::new()
-    };
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamMemoryUsage {
+    pub active_streams: usize,
+    pub accumulated_bytes: usize,
+}
 
-    Ok(content)
+#[tauri::command]
+async fn get_stream_memory_usage(memory: tauri::State<'_, StreamMemory>) -> Result<StreamMemoryUsage, String> {
+    Ok(StreamMemoryUsage {
+        active_streams: memory.streams.load(Ordering::Relaxed),
+        accumulated_bytes: memory.bytes.load(Ordering::Relaxed),
+    })
 }
 
-async fn proxy_chat_stream(
-    api_endpoint: String,
-    api_key: Option<String>,
-    request: ChatRequest,
-) -> Result<impl futures::Stream<Item = Result<String, std::io::Error>>, String> {
-    let client = build_http_client(300)?;
+/// Appends as much of `text` as fits under `cap`, cutting on a char
+/// boundary. Returns the bytes added and whether anything was left out.
+fn push_capped(content: &mut String, text: &str, cap: usize) -> (usize, bool) {
+    let room = cap.saturating_sub(content.len());
+    if text.len() <= room {
+        content.push_str(text);
+        return (text.len(), false);
+    }
+    let mut end = room;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    content.push_str(&text[..end]);
+    (end, true)
+}
 
-    let mut req_builder = client
-        .post(&api_endpoint)
-        .header("Content-Type", "application/json");
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OllamaModel {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<String>,
+    /// Size in bytes; some gateways omit it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
 
-    req_builder = add_auth_headers(req_builder, api_key);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PullProgress {
+    #[serde(default)]
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
 
-    let response = req_builder
-        .json(&request)
-        .send()
-        .await
-        .map_err(|e| format!("Request failed: {}", e))?;
+/// Broad category of a failure, so the frontend can react to e.g. rate limits
+/// without parsing the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Auth,
+    RateLimit,
+    Upstream,
+    Network,
+    Parse,
+    /// Rejected locally before anything was sent.
+    Invalid,
+    /// Local persistence failed or held corrupt data.
+    Storage,
+}
 
-    if !response.status().is_success() {
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyError {
+    pub kind: ErrorKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl ProxyError {
+    fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, status: None, message: redact(&message.into()) }
+    }
+
+    fn network(e: impl std::fmt::Display) -> Self {
+        Self::new(ErrorKind::Network, format!("Request failed: {}", e))
+    }
+
+    fn parse(e: impl std::fmt::Display) -> Self {
+        Self::new(ErrorKind::Parse, format!("Failed to parse response: {}", e))
+    }
+
+    fn from_status(status: reqwest::StatusCode, body: &str) -> Self {
+        let kind = match status.as_u16() {
+            401 | 403 => ErrorKind::Auth,
+            429 => ErrorKind::RateLimit,
+            _ => ErrorKind::Upstream,
+        };
+        Self {
+            kind,
+            status: Some(status.as_u16()),
+            // Upstreams sometimes echo the rejected key back
+            message: redact(&format!("API error: {} - {}", status, body)),
+        }
+    }
+
+    /// Reads the body of a failed response into an error.
+    async fn from_response(response: reqwest::Response) -> Self {
         let status = response.status();
         let error_text = response
             .text()
             .await
             .unwrap_or_else(|_| "Failed to read error response".to_string());
-        return Err(format!("API error: {} - {}", status, error_text));
+        Self::from_status(status, &error_text)
     }
+}
 
-    let s = stream! {
-        let stream = response.bytes_stream();
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
 
-        use futures::TryStreamExt;
-        use tokio_util::io::StreamReader;
-        use tokio::io::AsyncBufReadExt;
+impl From<String> for ProxyError {
+    fn from(message: String) -> Self {
+        Self::new(ErrorKind::Invalid, message)
+    }
+}
 
-        let reader = StreamReader::new(stream.map_err(std::io::Error::other));
+#[derive(Debug, Clone, Serialize)]
+pub struct OllamaModelInfo {
+    pub name: String,
+    pub family: Option<String>,
+    pub parameter_size: Option<String>,
+    pub quantization_level: Option<String>,
+    pub context_length: Option<u64>,
+    pub template: Option<String>,
+}
 
-        let mut lines = tokio::io::BufReader::new(reader).lines();
+#[derive(Debug, Deserialize)]
+struct OllamaShowResponse {
+    #[serde(default)]
+    template: Option<String>,
+    #[serde(default)]
+    details: Option<OllamaShowDetails>,
+    /// GGUF metadata; keys are prefixed by architecture, e.g. `llama.context_length`.
+    #[serde(default)]
+    model_info: Option<serde_json::Map<String, serde_json::Value>>,
+}
 
-        while let Ok(Some(line)) = lines.next_line().await {
-            if line.trim().is_empty() {
-                yield Ok("\n".to_string());
-                continue;
-            }
-            
-            if line.trim().starts_with(':') {
-                continue;
-            }
+#[derive(Debug, Deserialize)]
+struct OllamaShowDetails {
+    #[serde(default)]
+    family: Option<String>,
+    #[serde(default)]
+    parameter_size: Option<String>,
+    #[serde(default)]
+    quantization_level: Option<String>,
+}
 
-            if line.trim() == "[DONE]" {
-                yield Ok("data: [DONE]\n".to_string());
-                yield Ok("\n".to_string());
-                break;
-            }
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaTagsResponse {
+    models: Vec<OllamaModel>,
+}
 
-            #[cfg(debug_assertions)]
-            eprintln!("Passing through SSE line: {}", line);
-            yield Ok(format!("{}\n", line));
-            yield Ok("\n".to_string());
-        }
-    };
+#[derive(Debug, Clone)]
+struct StreamContext {
+    token: CancellationToken,
+    /// True while the stream is paused. Only `send_chat_stream` watches it.
+    paused: Arc<tokio::sync::watch::Sender<bool>>,
+}
 
-    Ok(s)
+impl StreamContext {
+    fn new(token: CancellationToken) -> Self {
+        Self {
+            token,
+            paused: Arc::new(tokio::sync::watch::Sender::new(false)),
+        }
+    }
 }
 
-#[tauri::command]
-async fn send_chat_request(
+#[derive(Deserialize)]
+struct ProxyChatRequest {
+    /// Empty means "use the configured upstream".
+    #[serde(default)]
     api_endpoint: String,
     api_key: Option<String>,
     request: ChatRequest,
-) -> Result<String, String> {
-    proxy_chat_request(api_endpoint, api_key, request).await
+    /// Lets `/chat/cancel` stop this stream; ignored by `/chat`.
+    #[serde(default)]
+    stream_id: Option<String>,
 }
 
-#[tauri::command]
-async fn send_chat_stream(
+impl std::fmt::Debug for ProxyChatRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyChatRequest")
+            .field("api_endpoint", &self.api_endpoint)
+            .field("api_key", &self.api_key.as_ref().map(|_| "***"))
+            .field("request", &self.request)
+            .field("stream_id", &self.stream_id)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct ProxyCancelRequest {
+    stream_id: String,
+}
+
+#[derive(Deserialize)]
+struct ProxyModelsRequest {
+    #[serde(default)]
     api_endpoint: String,
     api_key: Option<String>,
-    request: ChatRequest,
-    window: tauri::Window,
-    stream_id: Option<String>,
-) -> Result<String, String> {
-    let stream_id = stream_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    #[serde(default)]
+    user_agent: Option<String>,
+}
 
-    // Store stream context for cancellation
-    let token_map = window.state::<TokenMap>();
-    let token = CancellationToken::new();
+#[derive(Deserialize)]
+struct ProxyPullRequest {
+    #[serde(default)]
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+}
 
-    {
-        let mut map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
-        map.insert(stream_id.clone(), StreamContext {
-            token: token.clone(),
-        });
-    }
+type TokenMap = Arc<Mutex<HashMap<String, StreamContext>>>;
 
-    let stream = proxy_chat_stream(api_endpoint, api_key, request).await?;
+/// Registers a cancellation token under `stream_id`. Ids are caller-chosen,
+/// so an id that is still in use is rejected rather than replaced: the
+/// existing stream keeps its token and events, and the caller must retry
+/// with a fresh id.
+fn register_stream(token_map: &TokenMap, stream_id: &str) -> Result<StreamContext, String> {
+    let mut map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+    if map.contains_key(stream_id) {
+        return Err(format!("Stream {} is already active", stream_id));
+    }
 
-    let mut stream_content = String::new();
-    use futures::StreamExt;
+    let ctx = StreamContext::new(CancellationToken::new());
+    map.insert(stream_id.to_string(), ctx.clone());
+    Ok(ctx)
+}
 
-    tokio::pin!(stream);
+const DEFAULT_CHAT_TIMEOUT_SECS: u64 = 300;
+/// Separate from the per-request timeout, so an unreachable endpoint fails
+/// fast while long generations still get the full `timeout_secs`.
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const SCAN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_STREAM_IDLE_TIMEOUT_SECS: u64 = 60;
+const STREAM_HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+const DEFAULT_STREAM_BATCH_INTERVAL_MS: u64 = 50;
 
-    while let Some(chunk_result) = stream.next().await {
-        if token.is_cancelled() {
+/// Emits buffered stream text as a single `stream-{id}` event.
+fn flush_stream_batch(window: &tauri::Window, stream_id: &str, pending: &mut String) {
+    if pending.is_empty() {
+        return;
+    }
+    #[cfg(debug_assertions)]
+    eprintln!("Emitting chunk: [{} bytes] '{}'", pending.len(), redact(pending));
+    let _ = window.emit(&format!("stream-{}", stream_id), std::mem::take(pending));
+}
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const RETRY_BASE_DELAY_MS: u64 = 500;
+const DEFAULT_PROXY_PORT: u16 = 8080;
+const DEFAULT_PROXY_MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+const DEFAULT_PROXY_REQUEST_TIMEOUT_SECS: u64 = 10;
+const PROXY_SHUTDOWN_TIMEOUT_SECS: u64 = 2;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const ANTHROPIC_DEFAULT_MAX_TOKENS: u64 = 4096;
+
+const STORE_FILE: &str = "neox-config.json";
+const PROXY_CONFIG_KEY: &str = "proxy_server";
+
+/// Default upstream used by routes that don't carry their own endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyUpstream {
+    pub endpoint: String,
+    pub api_key: Option<String>,
+}
+
+pub struct HttpState {
+    pub client: reqwest::Client,
+    proxy_routes: SharedProxyRoutes,
+    provider_defaults: SharedProviderDefaults,
+    /// Clients for non-default connect timeouts, which reqwest only
+    /// supports per client.
+    connect_clients: Mutex<HashMap<u64, reqwest::Client>>,
+}
+
+impl HttpState {
+    /// The shared client, or one built (once, then reused) for a different
+    /// connect timeout. Each of those keeps its own connection pool.
+    fn client_for(&self, connect_timeout_secs: Option<u64>) -> Result<reqwest::Client, String> {
+        let secs = match connect_timeout_secs {
+            Some(0) => return Err("Invalid timeout: connect_timeout_secs must be greater than 0".to_string()),
+            None | Some(DEFAULT_CONNECT_TIMEOUT_SECS) => return Ok(self.client.clone()),
+            Some(secs) => secs,
+        };
+
+        let mut clients = self.connect_clients.lock().map_err(|e| format!("Lock error: {}", e))?;
+        if let Some(client) = clients.get(&secs) {
+            return Ok(client.clone());
+        }
+        let client = build_http_client(self.proxy_routes.clone(), std::time::Duration::from_secs(secs))?;
+        clients.insert(secs, client.clone());
+        Ok(client)
+    }
+
+    fn provider_defaults(&self) -> ProviderDefaults {
+        snapshot_provider_defaults(&self.provider_defaults)
+    }
+}
+
+pub struct ProxyState {
+    pub server_handle: Mutex<Option<actix_web::dev::ServerHandle>>,
+    pub bound_addr: Mutex<Option<(String, u16)>>,
+    /// What the running server was started with, so re-applying the same
+    /// settings leaves it (and its in-flight streams) alone.
+    running_config: Mutex<Option<ProxyServerConfig>>,
+    /// Serializes start/stop so concurrent calls can't race two servers.
+    apply_lock: tokio::sync::Mutex<()>,
+    pub logger: Arc<ProxyLogger>,
+    pub limiter: Arc<ConcurrencyLimiter>,
+    pub upstream: Arc<Mutex<Option<ProxyUpstream>>>,
+    /// Streams served by the proxy, kept apart from the in-app streams.
+    streams: TokenMap,
+    /// Bumped on every start, so a finished server task can tell whether it
+    /// is still the current one.
+    generation: AtomicUsize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ProxyServerConfig {
+    enabled: bool,
+    port: u16,
+    #[serde(default = "default_proxy_host")]
+    host: String,
+    #[serde(default)]
+    auth_token: Option<String>,
+    #[serde(default)]
+    max_concurrent_requests: Option<usize>,
+    #[serde(default)]
+    max_body_bytes: Option<usize>,
+    /// How long a client may take to send the request head.
+    #[serde(default)]
+    request_timeout_secs: Option<u64>,
+    /// Origins allowed to make credentialed requests. Without any, every
+    /// origin is allowed but credentials are not.
+    #[serde(default)]
+    allowed_origins: Option<Vec<String>>,
+    /// Serve HTTPS. Without cert/key paths a self-signed certificate is
+    /// generated in the app data dir and reused.
+    #[serde(default)]
+    tls: bool,
+    #[serde(default)]
+    tls_cert_path: Option<String>,
+    #[serde(default)]
+    tls_key_path: Option<String>,
+}
+
+/// Reduces an origin like "https://example.com/" to the form browsers send
+/// in the `Origin` header.
+fn normalize_origin(origin: &str) -> Result<String, String> {
+    let url = url::Url::parse(origin.trim()).map_err(|e| format!("Invalid origin '{}': {}", origin, e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return Err(format!("Invalid origin '{}': expected http(s)://host[:port]", origin));
+    }
+    if url.path() != "/" || url.query().is_some() || url.fragment().is_some() {
+        return Err(format!("Invalid origin '{}': origins must not include a path", origin));
+    }
+    Ok(url.origin().ascii_serialization())
+}
+
+/// Returns the PEM cert and key paths for the proxy, generating a self-signed
+/// pair on first use when none were configured.
+fn proxy_tls_paths(
+    app: &tauri::AppHandle,
+    config: &ProxyServerConfig,
+) -> Result<(std::path::PathBuf, std::path::PathBuf), String> {
+    match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => return Ok((cert.into(), key.into())),
+        (None, None) => {}
+        _ => return Err("TLS requires both tls_cert_path and tls_key_path".to_string()),
+    }
+
+    let tls_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to resolve app data dir: {}", e))?
+        .join("tls");
+    let cert_path = tls_dir.join("proxy-cert.pem");
+    let key_path = tls_dir.join("proxy-key.pem");
+    if cert_path.exists() && key_path.exists() {
+        return Ok((cert_path, key_path));
+    }
+
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    if !names.contains(&config.host) && config.host != "0.0.0.0" && config.host != "::" {
+        names.push(config.host.clone());
+    }
+    let certified = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| format!("Failed to generate TLS certificate: {}", e))?;
+
+    std::fs::create_dir_all(&tls_dir).map_err(|e| format!("Failed to create TLS directory: {}", e))?;
+    std::fs::write(&cert_path, certified.cert.pem())
+        .map_err(|e| format!("Failed to write TLS certificate: {}", e))?;
+    std::fs::write(&key_path, certified.key_pair.serialize_pem())
+        .map_err(|e| format!("Failed to write TLS key: {}", e))?;
+    println!("Generated self-signed proxy certificate at {}", cert_path.display());
+
+    Ok((cert_path, key_path))
+}
+
+fn load_tls_config(cert_path: &std::path::Path, key_path: &std::path::Path) -> Result<rustls::ServerConfig, String> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to load TLS certificate {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Failed to load TLS key {}: {}", key_path.display(), e))?;
+
+    rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))
+}
+
+fn build_cors(allowed_origins: Option<&[String]>) -> Cors {
+    let cors = match allowed_origins {
+        Some(origins) if !origins.is_empty() => origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .supports_credentials(),
+        // The spec forbids credentials with a wildcard origin
+        _ => Cors::default().allow_any_origin(),
+    };
+
+    cors.allow_any_method()
+        .allow_any_header()
+        .expose_headers(["X-Request-Id", "X-Stream-Id"])
+        .max_age(3600)
+}
+
+fn default_proxy_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// Bearer token required by the proxy routes, if configured.
+struct ProxyAuth(Option<String>);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyStatus {
+    pub running: bool,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+const PROXY_LOG_MAX_BYTES: u64 = 5 * 1024 * 1024;
+const PROXY_LOG_BACKUPS: usize = 3;
+const PROXY_RECENT_LOG_CAPACITY: usize = 200;
+
+/// One proxied request, as shown in the in-app network inspector. Only the
+/// route and model are kept, never keys or message content.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyLogEntry {
+    pub timestamp: u64,
+    pub request_id: String,
+    pub route: String,
+    pub model: Option<String>,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub bytes: usize,
+}
+
+/// Opt-in request log for the HTTP proxy. Only request metadata is recorded;
+/// API keys and message bodies are never written.
+pub struct ProxyLogger {
+    enabled: AtomicBool,
+    path: Mutex<Option<PathBuf>>,
+    /// Most recent requests, oldest first. Kept regardless of `enabled`.
+    recent: Mutex<VecDeque<ProxyLogEntry>>,
+}
+
+impl ProxyLogger {
+    fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            path: Mutex::new(None),
+            recent: Mutex::new(VecDeque::with_capacity(PROXY_RECENT_LOG_CAPACITY)),
+        }
+    }
+
+    fn record(&self, request_id: &str, route: &str, model: Option<&str>, status: u16, started: std::time::Instant, bytes: usize) {
+        let Ok(mut recent) = self.recent.lock() else {
+            return;
+        };
+        if recent.len() >= PROXY_RECENT_LOG_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(ProxyLogEntry {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            request_id: request_id.to_string(),
+            route: route.to_string(),
+            model: model.map(str::to_string),
+            status,
+            duration_ms: started.elapsed().as_millis() as u64,
+            bytes,
+        });
+    }
+
+    fn log(&self, line: &str) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let Ok(path_lock) = self.path.lock() else {
+            return;
+        };
+        if let Some(path) = path_lock.as_ref() {
+            if let Err(e) = write_rotating_log(path, line) {
+                eprintln!("Failed to write proxy log: {}", e);
+            }
+        }
+    }
+}
+
+fn write_rotating_log(path: &Path, line: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    if std::fs::metadata(path).is_ok_and(|meta| meta.len() >= PROXY_LOG_MAX_BYTES) {
+        for i in (1..PROXY_LOG_BACKUPS).rev() {
+            let from = path.with_extension(format!("log.{}", i));
+            if from.exists() {
+                std::fs::rename(&from, path.with_extension(format!("log.{}", i + 1)))?;
+            }
+        }
+        std::fs::rename(path, path.with_extension("log.1"))?;
+    }
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "[{}] {}", timestamp, line)
+}
+
+fn summarize_chat_request(route: &str, req: &ProxyChatRequest, request_id: &str) -> String {
+    format!(
+        "POST {} request_id={} model={} messages={}",
+        route,
+        request_id,
+        req.request.model,
+        req.request.messages.len()
+    )
+}
+
+/// Correlates a proxied request across the client, the proxy log and the
+/// upstream. Taken from the client's `X-Request-Id` when usable.
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+async fn propagate_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    use actix_web::HttpMessage;
+
+    let request_id = req
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(actix_web::http::header::HeaderName::from_static("x-request-id"), value);
+    }
+    Ok(res)
+}
+
+/// Copies the request with the id attached as an upstream header.
+fn with_request_id(request: &ChatRequest, request_id: &str) -> ChatRequest {
+    let mut request = request.clone();
+    request
+        .headers
+        .get_or_insert_with(HashMap::new)
+        .insert("X-Request-Id".to_string(), request_id.to_string());
+    request
+}
+
+/// Logs the outcome of a proxied stream once it is dropped, so client
+/// disconnects are recorded as well as clean finishes and upstream errors.
+struct StreamLog {
+    logger: web::Data<ProxyLogger>,
+    summary: String,
+    request_id: String,
+    model: String,
+    started: std::time::Instant,
+    bytes: usize,
+    outcome: &'static str,
+}
+
+impl StreamLog {
+    fn record(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    fn finish(&mut self, outcome: &'static str) {
+        self.outcome = outcome;
+    }
+}
+
+impl Drop for StreamLog {
+    fn drop(&mut self) {
+        self.logger.log(&format!(
+            "{} status=200 bytes={} outcome={}",
+            self.summary, self.bytes, self.outcome
+        ));
+        self.logger.record(&self.request_id, "/chat/stream", Some(&self.model), 200, self.started, self.bytes);
+    }
+}
+
+fn is_loopback_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Compares secrets without stopping at the first differing byte, so the
+/// time taken reveals nothing but the length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn require_proxy_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let expected = req
+        .app_data::<web::Data<ProxyAuth>>()
+        .and_then(|auth| auth.0.clone());
+
+    if let Some(token) = expected {
+        let authorized = req
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()));
+
+        if !authorized && req.path() != "/health" {
+            return Ok(req.into_response(
+                HttpResponse::Unauthorized().json(serde_json::json!({"error": "Missing or invalid proxy auth token"})),
+            ));
+        }
+    }
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+/// Caps the number of in-flight proxy requests. A limit of 0 means unlimited,
+/// and the limit can be changed while the server is running.
+pub struct ConcurrencyLimiter {
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl ConcurrencyLimiter {
+    fn new() -> Self {
+        Self {
+            limit: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    fn try_acquire(self: &Arc<Self>) -> Option<ConcurrencyPermit> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut current = self.in_flight.load(Ordering::Acquire);
+        loop {
+            if limit != 0 && current >= limit {
+                return None;
+            }
+            match self.in_flight.compare_exchange_weak(current, current + 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(ConcurrencyPermit(self.clone())),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+struct ConcurrencyPermit(Arc<ConcurrencyLimiter>);
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Response body that holds a concurrency permit until it has been fully
+/// sent, so streaming responses count against the limit while they run.
+struct PermitBody {
+    body: BoxBody,
+    _permit: ConcurrencyPermit,
+}
+
+impl MessageBody for PermitBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> actix_web::body::BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<bytes::Bytes, Self::Error>>> {
+        std::pin::Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+async fn limit_concurrency(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let limiter = req
+        .app_data::<web::Data<ConcurrencyLimiter>>()
+        .map(|limiter| limiter.clone().into_inner());
+
+    let Some(limiter) = limiter.filter(|_| req.path() != "/health") else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let Some(permit) = limiter.try_acquire() else {
+        return Ok(req.into_response(
+            HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", "1"))
+                .json(serde_json::json!({"error": "Too many concurrent requests"})),
+        ));
+    };
+
+    let res = next.call(req).await?.map_into_boxed_body();
+    Ok(res.map_body(|_, body| BoxBody::new(PermitBody { body, _permit: permit })))
+}
+
+/// Turns JSON extractor failures into `{"error": ...}` bodies, with 413 for
+/// payloads over the configured limit.
+fn json_error_handler(err: actix_web::error::JsonPayloadError, _req: &actix_web::HttpRequest) -> Error {
+    use actix_web::error::JsonPayloadError;
+    use actix_web::http::StatusCode;
+
+    let status = match err {
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        _ => StatusCode::BAD_REQUEST,
+    };
+    let response = HttpResponse::build(status).json(serde_json::json!({"error": err.to_string()}));
+
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
+fn proxy_json_config(max_body_bytes: Option<usize>) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(max_body_bytes.unwrap_or(DEFAULT_PROXY_MAX_BODY_BYTES))
+        .error_handler(json_error_handler)
+}
+
+async fn handle_health() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+/// Picks the request's own endpoint and key, or the configured upstream when
+/// the request leaves `api_endpoint` empty.
+fn request_upstream(
+    upstream: &Mutex<Option<ProxyUpstream>>,
+    api_endpoint: &str,
+    api_key: &Option<String>,
+) -> Result<(String, Option<String>), String> {
+    if !api_endpoint.trim().is_empty() {
+        return Ok((api_endpoint.to_string(), api_key.clone()));
+    }
+
+    match upstream.lock().ok().and_then(|u| u.clone()) {
+        Some(upstream) => Ok((upstream.endpoint, api_key.clone().or(upstream.api_key))),
+        None => Err("Request has no api_endpoint and no upstream is configured".to_string()),
+    }
+}
+
+async fn handle_chat(
+    req: web::Json<ProxyChatRequest>,
+    request_id: web::ReqData<RequestId>,
+    logger: web::Data<ProxyLogger>,
+    client: web::Data<reqwest::Client>,
+    upstream: web::Data<Mutex<Option<ProxyUpstream>>>,
+    defaults: web::Data<RwLock<ProviderDefaults>>,
+) -> Result<HttpResponse, Error> {
+    let started = std::time::Instant::now();
+    let request_id = request_id.into_inner().0;
+    let model = req.request.model.as_str();
+    let summary = summarize_chat_request("/chat", &req, &request_id);
+    let (api_endpoint, api_key) = match request_upstream(&upstream, &req.api_endpoint, &req.api_key) {
+        Ok(target) => target,
+        Err(e) => {
+            logger.record(&request_id, "/chat", Some(model), 400, started, 0);
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e})));
+        }
+    };
+
+    let request = with_request_id(&req.request, &request_id);
+    let defaults = snapshot_provider_defaults(&defaults);
+    match proxy_chat_request(client.get_ref().clone(), &defaults, api_endpoint, api_key, request, DEFAULT_CHAT_TIMEOUT_SECS, DEFAULT_MAX_RETRIES).await {
+        Ok(result) => {
+            let body = serde_json::to_string(&result).unwrap_or_default();
+            logger.log(&format!("{} status=200", summary));
+            logger.record(&request_id, "/chat", Some(model), 200, started, body.len());
+            Ok(HttpResponse::Ok().content_type("application/json").body(body))
+        }
+        Err(e) => {
+            logger.log(&format!("{} status=500", summary));
+            logger.record(&request_id, "/chat", Some(model), 500, started, 0);
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({"error": e.to_string()})))
+        }
+    }
+}
+
+/// Removes a proxy stream's token once its response body is dropped.
+struct StreamRegistration {
+    streams: Arc<Mutex<HashMap<String, StreamContext>>>,
+    stream_id: String,
+}
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        if let Ok(mut map) = self.streams.lock() {
+            map.remove(&self.stream_id);
+        }
+    }
+}
+
+async fn handle_chat_stream(
+    req: web::Json<ProxyChatRequest>,
+    request_id: web::ReqData<RequestId>,
+    logger: web::Data<ProxyLogger>,
+    client: web::Data<reqwest::Client>,
+    streams: web::Data<Mutex<HashMap<String, StreamContext>>>,
+    upstream: web::Data<Mutex<Option<ProxyUpstream>>>,
+    defaults: web::Data<RwLock<ProviderDefaults>>,
+) -> Result<HttpResponse, Error> {
+    use futures::StreamExt;
+    use bytes::Bytes;
+
+    let started = std::time::Instant::now();
+    let request_id = request_id.into_inner().0;
+    let model = req.request.model.clone();
+    let summary = summarize_chat_request("/chat/stream", &req, &request_id);
+    let (api_endpoint, api_key) = match request_upstream(&upstream, &req.api_endpoint, &req.api_key) {
+        Ok(target) => target,
+        Err(e) => {
+            logger.record(&request_id, "/chat/stream", Some(&model), 400, started, 0);
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e})));
+        }
+    };
+    let stream_id = req.stream_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let token = CancellationToken::new();
+
+    {
+        let mut map = streams.lock().map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+        if map.contains_key(&stream_id) {
+            logger.log(&format!("{} status=409", summary));
+            logger.record(&request_id, "/chat/stream", Some(&model), 409, started, 0);
+            return Ok(HttpResponse::Conflict().json(serde_json::json!({"error": format!("Stream {} is already active", stream_id)})));
+        }
+        map.insert(stream_id.clone(), StreamContext::new(token.clone()));
+    }
+    let registration = StreamRegistration {
+        streams: streams.into_inner(),
+        stream_id: stream_id.clone(),
+    };
+
+    let request = with_request_id(&req.request, &request_id);
+    let defaults = snapshot_provider_defaults(&defaults);
+    match proxy_chat_stream(client.get_ref().clone(), &defaults, api_endpoint, api_key, request, DEFAULT_CHAT_TIMEOUT_SECS, false).await {
+        Ok((headers, stream)) => {
+            let mut log = StreamLog {
+                logger,
+                summary,
+                request_id: request_id.clone(),
+                model,
+                started,
+                bytes: 0,
+                outcome: "aborted",
+            };
+
+            let mapped_stream = stream! {
+                let _registration = registration;
+                tokio::pin!(stream);
+
+                loop {
+                    let res = tokio::select! {
+                        _ = token.cancelled() => {
+                            log.finish("cancelled");
+                            return;
+                        }
+                        res = stream.next() => match res {
+                            Some(res) => res,
+                            None => break,
+                        },
+                    };
+
+                    match res {
+                        Ok(s) => {
+                            log.record(s.len());
+                            yield Ok(Bytes::from(s));
+                        }
+                        Err(e) => {
+                            log.finish("errored");
+                            yield Err(actix_web::error::ErrorInternalServerError(e));
+                            return;
+                        }
+                    }
+                }
+                log.finish("completed");
+            };
+
+            let mut response = HttpResponse::Ok();
+            response.content_type("text/event-stream").insert_header(("X-Stream-Id", stream_id));
+            for header in headers {
+                response.insert_header(header);
+            }
+            Ok(response.streaming(mapped_stream))
+        }
+        Err(e) => {
+            logger.log(&format!("{} status=500", summary));
+            logger.record(&request_id, "/chat/stream", Some(&model), 500, started, 0);
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({"error": e.to_string()})))
+        }
+    }
+}
+
+async fn handle_chat_cancel(
+    req: web::Json<ProxyCancelRequest>,
+    request_id: web::ReqData<RequestId>,
+    logger: web::Data<ProxyLogger>,
+    streams: web::Data<Mutex<HashMap<String, StreamContext>>>,
+) -> Result<HttpResponse, Error> {
+    let ctx = streams
+        .lock()
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .remove(&req.stream_id);
+
+    match ctx {
+        Some(ctx) => {
+            ctx.token.cancel();
+            logger.log(&format!("POST /chat/cancel request_id={} stream_id={} status=200", request_id.0, req.stream_id));
+            Ok(HttpResponse::Ok().json(serde_json::json!({"cancelled": true})))
+        }
+        None => {
+            logger.log(&format!("POST /chat/cancel request_id={} stream_id={} status=404", request_id.0, req.stream_id));
+            Ok(HttpResponse::NotFound().json(serde_json::json!({"error": format!("Unknown stream: {}", req.stream_id)})))
+        }
+    }
+}
+
+async fn handle_models(
+    req: web::Json<ProxyModelsRequest>,
+    request_id: web::ReqData<RequestId>,
+    logger: web::Data<ProxyLogger>,
+    client: web::Data<reqwest::Client>,
+    upstream: web::Data<Mutex<Option<ProxyUpstream>>>,
+) -> Result<HttpResponse, Error> {
+    let started = std::time::Instant::now();
+    let request_id = request_id.into_inner().0;
+    let (api_endpoint, api_key) = match request_upstream(&upstream, &req.api_endpoint, &req.api_key) {
+        Ok(target) => target,
+        Err(e) => {
+            logger.record(&request_id, "/models", None, 400, started, 0);
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e})));
+        }
+    };
+
+    match proxy_scan_ollama_models(client.get_ref().clone(), api_endpoint, api_key, req.user_agent.clone()).await {
+        Ok(models) => {
+            let body = serde_json::json!({"models": models}).to_string();
+            logger.log(&format!("POST /models request_id={} status=200", request_id));
+            logger.record(&request_id, "/models", None, 200, started, body.len());
+            Ok(HttpResponse::Ok().content_type("application/json").body(body))
+        }
+        Err(e) => {
+            logger.log(&format!("POST /models request_id={} status=500", request_id));
+            logger.record(&request_id, "/models", None, 500, started, 0);
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({"error": e.to_string()})))
+        }
+    }
+}
+
+async fn handle_models_pull(
+    req: web::Json<ProxyPullRequest>,
+    request_id: web::ReqData<RequestId>,
+    logger: web::Data<ProxyLogger>,
+    client: web::Data<reqwest::Client>,
+    upstream: web::Data<Mutex<Option<ProxyUpstream>>>,
+) -> Result<HttpResponse, Error> {
+    use futures::StreamExt;
+    use bytes::Bytes;
+
+    let summary = format!("POST /models/pull request_id={} model={}", request_id.0, req.model);
+    let (api_endpoint, api_key) = match request_upstream(&upstream, &req.api_endpoint, &req.api_key) {
+        Ok(target) => target,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e}))),
+    };
+
+    match proxy_pull_ollama_model(client.get_ref().clone(), api_endpoint, api_key, req.model.clone()).await {
+        Ok(stream) => {
+            logger.log(&format!("{} status=200", summary));
+
+            let mapped_stream = stream.map(|res| {
+                match res {
+                    Ok(progress) => {
+                        let data = serde_json::to_string(&progress).unwrap_or_default();
+                        if progress.error.is_some() {
+                            Ok(Bytes::from(format!("event: error\ndata: {}\n\n", data)))
+                        } else {
+                            Ok(Bytes::from(format!("data: {}\n\n", data)))
+                        }
+                    }
+                    Err(e) => Err(actix_web::error::ErrorInternalServerError(e)),
+                }
+            });
+
+            Ok(HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .streaming(mapped_stream))
+        }
+        Err(e) => {
+            logger.log(&format!("{} status=500", summary));
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({"error": e.to_string()})))
+        }
+    }
+}
+
+fn openai_error(status: actix_web::http::StatusCode, message: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({
+        "error": { "message": message.to_string(), "type": "proxy_error" }
+    }))
+}
+
+/// OpenAI-compatible passthrough: the body is forwarded verbatim to the
+/// configured upstream and the response (JSON or SSE) is relayed as-is, so
+/// existing OpenAI SDKs work by pointing their base URL at the proxy.
+async fn handle_openai_chat_completions(
+    body: web::Json<serde_json::Value>,
+    request_id: web::ReqData<RequestId>,
+    upstream: web::Data<Mutex<Option<ProxyUpstream>>>,
+    logger: web::Data<ProxyLogger>,
+    client: web::Data<reqwest::Client>,
+) -> Result<HttpResponse, Error> {
+    use actix_web::http::StatusCode;
+    use futures::TryStreamExt;
+
+    let body = body.into_inner();
+    let model = body.get("model").and_then(|m| m.as_str()).unwrap_or_default();
+    let summary = format!("POST /v1/chat/completions request_id={} model={}", request_id.0, model);
+    let is_stream = body.get("stream").and_then(|s| s.as_bool()).unwrap_or(false);
+
+    let Some(upstream) = upstream.lock().ok().and_then(|u| u.clone()) else {
+        logger.log(&format!("{} status=503", summary));
+        return Ok(openai_error(StatusCode::SERVICE_UNAVAILABLE, "No upstream configured for the proxy"));
+    };
+
+    let endpoint = match normalize_endpoint(&upstream.endpoint) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            logger.log(&format!("{} status=500", summary));
+            return Ok(openai_error(StatusCode::INTERNAL_SERVER_ERROR, e));
+        }
+    };
+
+    let mut req_builder = client
+        .post(endpoint)
+        .header("Content-Type", "application/json")
+        .header("X-Request-Id", request_id.0.as_str());
+    req_builder = add_auth_headers(req_builder, upstream.api_key);
+    if !is_stream {
+        req_builder = req_builder.timeout(std::time::Duration::from_secs(DEFAULT_CHAT_TIMEOUT_SECS));
+    }
+
+    let response = match tokio::time::timeout(
+        std::time::Duration::from_secs(DEFAULT_CHAT_TIMEOUT_SECS),
+        req_builder.json(&body).send(),
+    )
+    .await
+    {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            logger.log(&format!("{} status=502", summary));
+            return Ok(openai_error(StatusCode::BAD_GATEWAY, format!("Request failed: {}", e)));
+        }
+        Err(_) => {
+            logger.log(&format!("{} status=504", summary));
+            return Ok(openai_error(StatusCode::GATEWAY_TIMEOUT, "Upstream timed out"));
+        }
+    };
+
+    let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/json")
+        .to_string();
+    logger.log(&format!("{} status={}", summary, status.as_u16()));
+
+    if is_stream && status.is_success() {
+        let stream = response
+            .bytes_stream()
+            .map_err(actix_web::error::ErrorBadGateway);
+        return Ok(HttpResponse::build(status)
+            .content_type("text/event-stream")
+            .streaming(stream));
+    }
+
+    match response.bytes().await {
+        Ok(bytes) => Ok(HttpResponse::build(status).content_type(content_type).body(bytes)),
+        Err(e) => Ok(openai_error(StatusCode::BAD_GATEWAY, format!("Failed to read upstream response: {}", e))),
+    }
+}
+
+const DEFAULT_USER_AGENT: &str = concat!("Miscuay/", env!("CARGO_PKG_VERSION"), " (", env!("CARGO_PKG_NAME"), ")");
+
+const OUTBOUND_PROXY_KEY: &str = "outbound_proxy";
+const DEFAULT_NO_PROXY: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+/// Proxy for upstream requests, as saved in the store.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OutboundProxyConfig {
+    /// http://, https://, socks5:// or socks5h:// URL. Without one the
+    /// HTTPS_PROXY / HTTP_PROXY / ALL_PROXY environment variables are used.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Hosts reached directly: "localhost", "10.0.0.5", ".corp.example"
+    /// (the domain and its subdomains) or "*". Defaults to loopback only, so
+    /// a local Ollama keeps working.
+    #[serde(default)]
+    pub no_proxy: Option<Vec<String>>,
+    /// Per-host proxy, keyed by "host" or "host:port". An empty value or
+    /// "direct" bypasses the proxy for that host.
+    #[serde(default)]
+    pub overrides: HashMap<String, String>,
+}
+
+/// The validated form of `OutboundProxyConfig`, consulted on every request.
+#[derive(Debug, Default)]
+struct ProxyRoutes {
+    default: Option<url::Url>,
+    no_proxy: Vec<String>,
+    overrides: HashMap<String, Option<url::Url>>,
+}
+
+type SharedProxyRoutes = Arc<RwLock<ProxyRoutes>>;
+
+fn parse_proxy_url(proxy: &str) -> Result<url::Url, String> {
+    let url = url::Url::parse(proxy.trim()).map_err(|e| format!("Invalid proxy URL '{}': {}", proxy, e))?;
+    if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(format!(
+            "Invalid proxy URL '{}': scheme must be http, https, socks5 or socks5h",
+            proxy
+        ));
+    }
+    if url.host_str().is_none() {
+        return Err(format!("Invalid proxy URL '{}': missing host", proxy));
+    }
+    Ok(url)
+}
+
+/// The first proxy set in the environment, under either case convention.
+fn env_proxy(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .flat_map(|name| [name.to_string(), name.to_lowercase()])
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.trim().is_empty()))
+}
+
+impl ProxyRoutes {
+    fn from_config(config: &OutboundProxyConfig) -> Result<Self, String> {
+        let default = match config.url.as_deref().map(str::trim).filter(|url| !url.is_empty()) {
+            Some(url) => Some(parse_proxy_url(url)?),
+            None => None,
+        };
+
+        let no_proxy = match &config.no_proxy {
+            Some(hosts) => hosts
+                .iter()
+                .map(|host| host.trim().to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+            None => DEFAULT_NO_PROXY.iter().map(|host| host.to_string()).collect(),
+        };
+
+        let mut overrides = HashMap::new();
+        for (host, proxy) in &config.overrides {
+            let proxy = match proxy.trim() {
+                "" | "direct" => None,
+                proxy => Some(parse_proxy_url(proxy)?),
+            };
+            overrides.insert(host.trim().to_ascii_lowercase(), proxy);
+        }
+
+        Ok(Self { default, no_proxy, overrides })
+    }
+
+    fn bypasses(&self, host: &str) -> bool {
+        self.no_proxy.iter().any(|entry| {
+            let domain = entry.trim_start_matches('*').trim_start_matches('.');
+            entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+        })
+    }
+
+    /// Picks the proxy for one upstream URL, or `None` to connect directly.
+    fn resolve(&self, target: &url::Url) -> Option<url::Url> {
+        let host = target.host_str()?.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let host_port = target.port_or_known_default().map(|port| format!("{}:{}", host, port));
+
+        if let Some(proxy) = host_port.and_then(|key| self.overrides.get(&key)).or_else(|| self.overrides.get(&host)) {
+            return proxy.clone();
+        }
+        if self.bypasses(&host) {
+            return None;
+        }
+        if let Some(proxy) = &self.default {
+            return Some(proxy.clone());
+        }
+
+        let from_env = match target.scheme() {
+            "https" => env_proxy(&["HTTPS_PROXY", "ALL_PROXY"]),
+            _ => env_proxy(&["HTTP_PROXY", "ALL_PROXY"]),
+        };
+        let env_bypassed = env_proxy(&["NO_PROXY"]).is_some_and(|hosts| {
+            let routes = ProxyRoutes {
+                no_proxy: hosts.split(',').map(|h| h.trim().to_ascii_lowercase()).filter(|h| !h.is_empty()).collect(),
+                ..Default::default()
+            };
+            routes.bypasses(&host)
+        });
+        from_env.filter(|_| !env_bypassed).and_then(|proxy| parse_proxy_url(&proxy).ok())
+    }
+}
+
+fn load_outbound_proxy_config(app: &tauri::AppHandle) -> OutboundProxyConfig {
+    app.store(STORE_FILE)
+        .ok()
+        .and_then(|store| store.get(OUTBOUND_PROXY_KEY))
+        .and_then(|value| serde_json::from_value(value).ok())
+        .unwrap_or_default()
+}
+
+/// Routes upstream requests through a proxy. Applies to new connections
+/// immediately; connections already pooled finish on their old route.
+#[tauri::command]
+async fn set_outbound_proxy(
+    app: tauri::AppHandle,
+    config: OutboundProxyConfig,
+    http: tauri::State<'_, HttpState>,
+) -> Result<(), String> {
+    let routes = ProxyRoutes::from_config(&config)?;
+
+    let store = app.store(STORE_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(&config).map_err(|e| e.to_string())?;
+    store.set(OUTBOUND_PROXY_KEY, value);
+    store.save().map_err(|e| e.to_string())?;
+
+    *http.proxy_routes.write().map_err(|e| format!("Lock error: {}", e))? = routes;
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_outbound_proxy(app: tauri::AppHandle) -> Result<OutboundProxyConfig, String> {
+    Ok(load_outbound_proxy_config(&app))
+}
+
+/// Builds the shared HTTP client. Timeouts are applied per request so one
+/// client (and its connection pool) can serve chat, streaming and model scans.
+fn build_http_client(proxy_routes: SharedProxyRoutes, connect_timeout: std::time::Duration) -> Result<reqwest::Client, String> {
+    let proxy = reqwest::Proxy::custom(move |target| proxy_routes.read().ok()?.resolve(target));
+
+    // Advertises Accept-Encoding and transparently decodes compressed bodies,
+    // including SSE from gateways that gzip their streams
+    reqwest::Client::builder()
+        .proxy(proxy)
+        .connect_timeout(connect_timeout)
+        .user_agent(DEFAULT_USER_AGENT)
+        .gzip(true)
+        .brotli(true)
+        .deflate(true)
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+/// Trims and validates a user-supplied endpoint before any network call.
+/// Endpoints without a scheme (e.g. `localhost:11434/v1`) default to http.
+fn normalize_endpoint(api_endpoint: &str) -> Result<url::Url, String> {
+    let trimmed = api_endpoint.trim();
+    if trimmed.is_empty() {
+        return Err("API endpoint is empty".to_string());
+    }
+
+    let with_scheme = if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        format!("http://{}", trimmed)
+    };
+
+    let url = url::Url::parse(&with_scheme)
+        .map_err(|e| format!("Invalid API endpoint URL '{}': {}", trimmed, e))?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "Invalid API endpoint URL '{}': scheme must be http or https, got '{}'",
+            trimmed,
+            url.scheme()
+        ));
+    }
+
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(format!("Invalid API endpoint URL '{}': missing host", trimmed));
+    }
+
+    Ok(url)
+}
+
+fn resolve_timeout(timeout_secs: Option<u64>) -> Result<u64, String> {
+    match timeout_secs {
+        Some(0) => Err("Invalid timeout: timeout_secs must be greater than 0".to_string()),
+        Some(secs) => Ok(secs),
+        None => Ok(DEFAULT_CHAT_TIMEOUT_SECS),
+    }
+}
+
+/// Masks credentials in text bound for the terminal or an error message:
+/// bearer tokens, API key headers and query parameters, and `sk-` style keys.
+fn redact(text: &str) -> String {
+    const PREFIXES: [&str; 3] = ["bearer ", "x-api-key: ", "x-goog-api-key: "];
+    const PARAMS: [&str; 5] = ["key=", "api_key=", "apikey=", "access_token=", "token="];
+
+    fn is_secret_byte(b: u8) -> bool {
+        b.is_ascii_alphanumeric() || b"-_.~+/=".contains(&b)
+    }
+
+    let bytes = text.as_bytes();
+    let starts_with_at = |i: usize, marker: &str| {
+        bytes.len() >= i + marker.len() && bytes[i..i + marker.len()].eq_ignore_ascii_case(marker.as_bytes())
+    };
+
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let after_boundary = i == 0 || !bytes[i - 1].is_ascii_alphanumeric();
+        let marker = PREFIXES
+            .iter()
+            .find(|m| after_boundary && starts_with_at(i, m))
+            .or_else(|| {
+                PARAMS
+                    .iter()
+                    .find(|m| (i == 0 || matches!(bytes[i - 1], b'?' | b'&')) && starts_with_at(i, m))
+            })
+            .map(|m| &text[i..i + m.len()])
+            .or_else(|| (after_boundary && starts_with_at(i, "sk-")).then(|| &text[i..i + 3]));
+
+        if let Some(marker) = marker {
+            let secret_start = i + marker.len();
+            let secret_len = bytes[secret_start..].iter().take_while(|b| is_secret_byte(**b)).count();
+            // "sk-" on its own is too common in prose to mask short words
+            if secret_len > 0 && (marker != "sk-" || secret_len >= 8) {
+                out.push_str(marker);
+                out.push_str("***");
+                i = secret_start + secret_len;
+                continue;
+            }
+        }
+
+        let ch = text[i..].chars().next().unwrap_or_default();
+        out.push(ch);
+        i += ch.len_utf8();
+    }
+
+    out
+}
+
+/// Overrides the client's default `User-Agent` when one is given.
+fn add_user_agent(builder: reqwest::RequestBuilder, user_agent: Option<&str>) -> reqwest::RequestBuilder {
+    match user_agent.filter(|ua| !ua.trim().is_empty()) {
+        Some(ua) => builder.header(reqwest::header::USER_AGENT, ua),
+        None => builder,
+    }
+}
+
+fn add_auth_headers(builder: reqwest::RequestBuilder, api_key: Option<String>) -> reqwest::RequestBuilder {
+    if let Some(key) = api_key {
+        builder.header("Authorization", format!("Bearer {}", key))
+    } else {
+        builder
+    }
+}
+
+/// Applies caller-supplied headers. Content-Type is managed by the proxy
+/// itself and only replaced when `override_content_type` is set; callers
+/// must apply these before the JSON body, which only fills in a missing one.
+fn add_custom_headers(
+    mut builder: reqwest::RequestBuilder,
+    headers: Option<&HashMap<String, String>>,
+    override_content_type: bool,
+) -> Result<reqwest::RequestBuilder, String> {
+    let Some(headers) = headers else {
+        return Ok(builder);
+    };
+
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("content-type") && !override_content_type {
+            #[cfg(debug_assertions)]
+            eprintln!("Ignoring custom Content-Type header");
+            continue;
+        }
+
+        let header_name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name: {}", name))?;
+        let header_value = reqwest::header::HeaderValue::from_str(value)
+            .map_err(|_| format!("Invalid value for header {}", name))?;
+        builder = builder.header(header_name, header_value);
+    }
+
+    Ok(builder)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Provider {
+    OpenAi,
+    Ollama,
+    Anthropic,
+    Gemini,
+}
+
+fn resolve_provider(provider: Option<&str>, api_endpoint: &str) -> Result<Provider, String> {
+    if let Some(name) = provider {
+        return match name.trim().to_ascii_lowercase().as_str() {
+            "openai" => Ok(Provider::OpenAi),
+            "ollama" => Ok(Provider::Ollama),
+            "anthropic" => Ok(Provider::Anthropic),
+            "gemini" => Ok(Provider::Gemini),
+            other => Err(format!("Unsupported provider: {}", other)),
+        };
+    }
+
+    let is_anthropic = url::Url::parse(api_endpoint).is_ok_and(|url| {
+        url.host_str().is_some_and(|host| host.ends_with("anthropic.com"))
+            || url.path().trim_end_matches('/').ends_with("/v1/messages")
+    });
+
+    let is_ollama = url::Url::parse(api_endpoint).is_ok_and(|url| {
+        let path = url.path().trim_end_matches('/');
+        path.ends_with("/api/chat") || path.ends_with("/api/generate")
+    });
+
+    let is_gemini = url::Url::parse(api_endpoint)
+        .is_ok_and(|url| url.host_str() == Some("generativelanguage.googleapis.com"));
+
+    if is_anthropic {
+        Ok(Provider::Anthropic)
+    } else if is_gemini {
+        Ok(Provider::Gemini)
+    } else if is_ollama {
+        Ok(Provider::Ollama)
+    } else {
+        Ok(Provider::OpenAi)
+    }
+}
+
+fn provider_id(provider: Provider) -> &'static str {
+    match provider {
+        Provider::OpenAi => "openai",
+        Provider::Ollama => "ollama",
+        Provider::Anthropic => "anthropic",
+        Provider::Gemini => "gemini",
+    }
+}
+
+const PROVIDER_DEFAULTS_KEY: &str = "provider_defaults";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProviderDefault {
+    /// Used when a request names this provider but leaves the endpoint empty.
+    pub base_url: Option<String>,
+    /// Friendly name -> model id sent upstream.
+    pub aliases: HashMap<String, String>,
+}
+
+/// Per-provider defaults, keyed by provider id ("openai", "ollama", ...).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProviderDefaults(pub HashMap<String, ProviderDefault>);
+
+type SharedProviderDefaults = Arc<RwLock<ProviderDefaults>>;
+
+impl ProviderDefaults {
+    /// Checks the config and rewrites provider ids to their canonical form.
+    fn normalized(self) -> Result<Self, String> {
+        let mut defaults = HashMap::new();
+        for (name, entry) in self.0 {
+            let id = provider_id(resolve_provider(Some(&name), "")?);
+            if let Some(base_url) = entry.base_url.as_deref().filter(|url| !url.trim().is_empty()) {
+                normalize_endpoint(base_url).map_err(|e| format!("Invalid base URL for {}: {}", id, e))?;
+            }
+            if let Some((alias, _)) = entry.aliases.iter().find(|(alias, model)| alias.trim().is_empty() || model.trim().is_empty()) {
+                return Err(format!("Invalid alias '{}' for {}: names and model ids must not be empty", alias, id));
+            }
+            if defaults.insert(id.to_string(), entry).is_some() {
+                return Err(format!("Duplicate defaults for provider {}", id));
+            }
+        }
+        Ok(Self(defaults))
+    }
+
+    /// The base URL to use when a request leaves the endpoint empty.
+    fn endpoint_for(&self, provider: Option<&str>, api_endpoint: String) -> String {
+        if !api_endpoint.trim().is_empty() {
+            return api_endpoint;
+        }
+        provider
+            .and_then(|name| resolve_provider(Some(name), "").ok())
+            .and_then(|provider| self.0.get(provider_id(provider))?.base_url.clone())
+            .unwrap_or(api_endpoint)
+    }
+
+    /// The upstream id for `model`, or `model` itself when it is not an alias.
+    fn resolve_model(&self, provider: Provider, model: String) -> String {
+        self.0
+            .get(provider_id(provider))
+            .and_then(|entry| entry.aliases.get(model.trim()))
+            .cloned()
+            .unwrap_or(model)
+    }
+}
+
+fn snapshot_provider_defaults(defaults: &RwLock<ProviderDefaults>) -> ProviderDefaults {
+    defaults.read().map(|d| d.clone()).unwrap_or_default()
+}
+
+fn load_provider_defaults(app: &tauri::AppHandle) -> ProviderDefaults {
+    app.store(STORE_FILE)
+        .ok()
+        .and_then(|store| store.get(PROVIDER_DEFAULTS_KEY))
+        .and_then(|value| serde_json::from_value::<ProviderDefaults>(value).ok())
+        .and_then(|defaults| defaults.normalized().ok())
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+async fn get_provider_defaults(app: tauri::AppHandle) -> Result<ProviderDefaults, String> {
+    Ok(load_provider_defaults(&app))
+}
+
+/// Saves base URLs and model aliases. Takes effect for the next request.
+#[tauri::command]
+async fn set_provider_defaults(
+    app: tauri::AppHandle,
+    defaults: ProviderDefaults,
+    http: tauri::State<'_, HttpState>,
+) -> Result<(), String> {
+    let defaults = defaults.normalized()?;
+
+    let store = app.store(STORE_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(&defaults).map_err(|e| e.to_string())?;
+    store.set(PROVIDER_DEFAULTS_KEY, value);
+    store.save().map_err(|e| e.to_string())?;
+
+    *http.provider_defaults.write().map_err(|e| format!("Lock error: {}", e))? = defaults;
+    Ok(())
+}
+
+fn add_anthropic_headers(builder: reqwest::RequestBuilder, api_key: Option<String>) -> reqwest::RequestBuilder {
+    let builder = builder.header("anthropic-version", ANTHROPIC_VERSION);
+    if let Some(key) = api_key {
+        builder.header("x-api-key", key)
+    } else {
+        builder
+    }
+}
+
+/// Translates a ChatRequest into an Anthropic Messages API body. System
+/// messages move to the top-level `system` field and `options` are flattened
+/// into the body, since the API rejects unknown keys.
+fn build_anthropic_body(request: &ChatRequest) -> serde_json::Value {
+    let mut system = Vec::new();
+    let mut messages = Vec::new();
+
+    for message in &request.messages {
+        if message.role == "system" {
+            match &message.content {
+                serde_json::Value::String(text) => system.push(text.clone()),
+                other => system.push(other.to_string()),
+            }
+        } else {
+            messages.push(serde_json::json!({
+                "role": message.role,
+                "content": message.content,
+            }));
+        }
+    }
+
+    let mut body = serde_json::json!({
+        "model": request.model,
+        "messages": messages,
+        "max_tokens": request.max_tokens.unwrap_or(ANTHROPIC_DEFAULT_MAX_TOKENS),
+        "stream": request.stream,
+    });
+
+    if let Some(temperature) = request.temperature {
+        body["temperature"] = temperature.into();
+    }
+    if let Some(top_p) = request.top_p {
+        body["top_p"] = top_p.into();
+    }
+
+    if !system.is_empty() {
+        body["system"] = serde_json::Value::String(system.join("\n\n"));
+    }
+
+    if let Some(serde_json::Value::Object(options)) = &request.options {
+        for (key, value) in options {
+            body[key] = value.clone();
+        }
+    }
+
+    body
+}
+
+/// Ollama reads a bare number as seconds but rejects unitless strings like "-1".
+fn keep_alive_value(keep_alive: &str) -> serde_json::Value {
+    match keep_alive.trim().parse::<i64>() {
+        Ok(secs) => secs.into(),
+        Err(_) => keep_alive.trim().into(),
+    }
+}
+
+/// Builds the upstream JSON body, mapping the typed sampling fields onto the
+/// provider's names: top-level keys for OpenAI, `options.num_predict` etc. for
+/// Ollama's native API.
+fn build_chat_body(request: &ChatRequest, provider: Provider) -> serde_json::Value {
+    match provider {
+        Provider::Anthropic => return build_anthropic_body(request),
+        Provider::Gemini => return build_gemini_body(request),
+        Provider::OpenAi | Provider::Ollama => {}
+    }
+
+    let mut body = serde_json::to_value(request).unwrap_or_default();
+    let raw_options = match &request.options {
+        Some(serde_json::Value::Object(options)) => options.clone(),
+        _ => serde_json::Map::new(),
+    };
+
+    let max_tokens_key = if provider == Provider::Ollama { "num_predict" } else { "max_tokens" };
+    let mut translated = serde_json::Map::new();
+    if let Some(max_tokens) = request.max_tokens {
+        translated.insert(max_tokens_key.to_string(), max_tokens.into());
+    }
+    if let Some(temperature) = request.temperature {
+        translated.insert("temperature".to_string(), temperature.into());
+    }
+    if let Some(top_p) = request.top_p {
+        translated.insert("top_p".to_string(), top_p.into());
+    }
+
+    if provider == Provider::Ollama {
+        if let Some(keep_alive) = &request.keep_alive {
+            body["keep_alive"] = keep_alive_value(keep_alive);
+        }
+        if !translated.is_empty() {
+            translated.extend(raw_options);
+            body["options"] = serde_json::Value::Object(translated);
+        }
+    } else {
+        for (key, value) in translated {
+            let value = raw_options.get(&key).cloned().unwrap_or(value);
+            body[key] = value;
+        }
+    }
+
+    body
+}
+
+const CONTENT_PART_TYPES: &[&str] = &["text", "image_url", "input_audio", "file", "image"];
+
+/// Checks multimodal content arrays locally, since upstreams tend to answer a
+/// malformed part with an unhelpful 400.
+fn validate_message_content(messages: &[ChatMessage]) -> Result<(), String> {
+    for (index, message) in messages.iter().enumerate() {
+        let parts = match &message.content {
+            serde_json::Value::String(_) | serde_json::Value::Null => continue,
+            serde_json::Value::Array(parts) => parts,
+            _ => {
+                return Err(format!(
+                    "Message {}: content must be a string or an array of parts",
+                    index
+                ))
+            }
+        };
+
+        for (part_index, part) in parts.iter().enumerate() {
+            if part.is_string() {
+                continue;
+            }
+            let kind = part
+                .as_object()
+                .and_then(|obj| obj.get("type"))
+                .and_then(|t| t.as_str())
+                .ok_or_else(|| {
+                    format!("Message {}, part {}: expected a string or an object with a \"type\"", index, part_index)
+                })?;
+
+            if !CONTENT_PART_TYPES.contains(&kind) {
+                return Err(format!(
+                    "Message {}, part {}: unsupported content type \"{}\" (expected one of {})",
+                    index,
+                    part_index,
+                    kind,
+                    CONTENT_PART_TYPES.join(", ")
+                ));
+            }
+            if kind == "text" && !part.get("text").is_some_and(|t| t.is_string()) {
+                return Err(format!("Message {}, part {}: text part is missing \"text\"", index, part_index));
+            }
+            if kind == "image_url" && part.get("image_url").is_none() {
+                return Err(format!(
+                    "Message {}, part {}: image_url part is missing \"image_url\"",
+                    index, part_index
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn add_gemini_headers(builder: reqwest::RequestBuilder, api_key: Option<String>) -> reqwest::RequestBuilder {
+    match api_key {
+        Some(key) if !key.is_empty() => builder.header("x-goog-api-key", key),
+        _ => builder,
+    }
+}
+
+/// Points a Gemini endpoint at (stream)generateContent. Accepts either a full
+/// `.../models/{model}:generateContent` URL or an API base like `/v1beta`.
+fn gemini_url(mut url: url::Url, model: &str, stream: bool) -> url::Url {
+    let method = if stream { "streamGenerateContent" } else { "generateContent" };
+    let path = url.path().trim_end_matches('/').to_string();
+
+    let new_path = match path.rsplit_once(':') {
+        Some((prefix, "generateContent" | "streamGenerateContent")) => format!("{}:{}", prefix, method),
+        _ if path.is_empty() => format!("/v1beta/models/{}:{}", model, method),
+        _ => format!("{}/models/{}:{}", path, model, method),
+    };
+    url.set_path(&new_path);
+
+    // alt=sse switches the stream from a JSON array to server-sent events
+    if stream && !url.query_pairs().any(|(key, _)| key == "alt") {
+        url.query_pairs_mut().append_pair("alt", "sse");
+    }
+
+    url
+}
+
+fn gemini_part(part: &serde_json::Value) -> Option<serde_json::Value> {
+    if let Some(text) = part.as_str() {
+        return Some(serde_json::json!({ "text": text }));
+    }
+
+    match part.get("type").and_then(|t| t.as_str()) {
+        Some("text") => Some(serde_json::json!({ "text": part.get("text")? })),
+        Some("image_url") => {
+            let url = part
+                .get("image_url")
+                .and_then(|i| i.get("url").or(Some(i)))
+                .and_then(|u| u.as_str())?;
+            match url.strip_prefix("data:").and_then(|rest| rest.split_once(";base64,")) {
+                Some((mime_type, data)) => Some(serde_json::json!({
+                    "inline_data": { "mime_type": mime_type, "data": data }
+                })),
+                None => Some(serde_json::json!({ "file_data": { "file_uri": url } })),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn build_gemini_body(request: &ChatRequest) -> serde_json::Value {
+    let mut system = Vec::new();
+    let mut contents = Vec::new();
+
+    for message in &request.messages {
+        let mut parts: Vec<serde_json::Value> = match &message.content {
+            serde_json::Value::Array(items) => items.iter().filter_map(gemini_part).collect(),
+            serde_json::Value::Null => Vec::new(),
+            other => gemini_part(other)
+                .or_else(|| Some(serde_json::json!({ "text": other.to_string() })))
+                .into_iter()
+                .collect(),
+        };
+        for image in message.images.iter().flatten() {
+            parts.push(serde_json::json!({
+                "inline_data": { "mime_type": "image/png", "data": image }
+            }));
+        }
+
+        match message.role.as_str() {
+            "system" => system.extend(parts),
+            // Gemini only knows "user" and "model"
+            "assistant" | "model" => contents.push(serde_json::json!({ "role": "model", "parts": parts })),
+            _ => contents.push(serde_json::json!({ "role": "user", "parts": parts })),
+        }
+    }
+
+    let mut generation_config = serde_json::Map::new();
+    if let Some(max_tokens) = request.max_tokens {
+        generation_config.insert("maxOutputTokens".to_string(), max_tokens.into());
+    }
+    if let Some(temperature) = request.temperature {
+        generation_config.insert("temperature".to_string(), temperature.into());
+    }
+    if let Some(top_p) = request.top_p {
+        generation_config.insert("topP".to_string(), top_p.into());
+    }
+    if let Some(serde_json::Value::Object(options)) = &request.options {
+        generation_config.extend(options.clone());
+    }
+
+    let mut body = serde_json::json!({ "contents": contents });
+    if !system.is_empty() {
+        body["systemInstruction"] = serde_json::json!({ "parts": system });
+    }
+    if !generation_config.is_empty() {
+        body["generationConfig"] = serde_json::Value::Object(generation_config);
+    }
+
+    body
+}
+
+/// Rewrites a Gemini stream chunk as an OpenAI-style delta, so the frontend
+/// and `collect_chat_stream` only ever see one chunk format.
+fn translate_gemini_event(mut event: SseEvent) -> SseEvent {
+    let Ok(chunk) = serde_json::from_str::<GeminiResponse>(&event.data) else {
+        return event;
+    };
+
+    let (reasoning, content) = chunk.text();
+    let mut delta = serde_json::Map::new();
+    if !content.is_empty() {
+        delta.insert("content".to_string(), content.into());
+    }
+    if !reasoning.is_empty() {
+        delta.insert("reasoning_content".to_string(), reasoning.into());
+    }
+
+    let mut translated = serde_json::json!({
+        "choices": [{ "delta": delta, "finish_reason": chunk.finish_reason() }]
+    });
+    if let Some(usage) = chunk.usage() {
+        translated["usage"] = serde_json::to_value(usage).unwrap_or_default();
+    }
+
+    event.data = translated.to_string();
+    event
+}
+
+/// Rewrites an Anthropic stream event as an OpenAI-style delta, like
+/// `translate_gemini_event`. Bookkeeping events such as `ping` yield
+/// nothing, and true is returned once `message_stop` ends the stream.
+/// `input_tokens` carries the prompt size from `message_start` over to the
+/// usage reported with `message_delta`.
+fn translate_anthropic_event(event: SseEvent, input_tokens: &mut u64) -> (Option<SseEvent>, bool) {
+    let Ok(payload) = serde_json::from_str::<serde_json::Value>(&event.data) else {
+        return (Some(event), false);
+    };
+
+    let index = payload["index"].as_u64();
+    let mut delta = serde_json::Map::new();
+    let mut finish_reason = None;
+    let mut usage = None;
+    match payload["type"].as_str().unwrap_or_default() {
+        "message_start" => {
+            *input_tokens = payload["message"]["usage"]["input_tokens"].as_u64().unwrap_or_default();
+            delta.insert("role".to_string(), "assistant".into());
+        }
+        "content_block_start" if payload["content_block"]["type"] == "tool_use" => {
+            let block = &payload["content_block"];
+            delta.insert("tool_calls".to_string(), serde_json::json!([{
+                "index": index,
+                "id": block["id"],
+                "type": "function",
+                "function": { "name": block["name"], "arguments": "" },
+            }]));
+        }
+        "content_block_delta" => {
+            let fragment = &payload["delta"];
+            match fragment["type"].as_str() {
+                Some("text_delta") => {
+                    delta.insert("content".to_string(), fragment["text"].clone());
+                }
+                Some("thinking_delta") => {
+                    delta.insert("reasoning_content".to_string(), fragment["thinking"].clone());
+                }
+                Some("input_json_delta") => {
+                    delta.insert("tool_calls".to_string(), serde_json::json!([{
+                        "index": index,
+                        "function": { "arguments": fragment["partial_json"] },
+                    }]));
+                }
+                _ => {}
+            }
+        }
+        "message_delta" => {
+            finish_reason = payload["delta"]["stop_reason"].as_str().map(normalize_finish_reason);
+            let completion_tokens = payload["usage"]["output_tokens"].as_u64().unwrap_or_default();
+            usage = Some(serde_json::json!({
+                "prompt_tokens": *input_tokens,
+                "completion_tokens": completion_tokens,
+                "total_tokens": *input_tokens + completion_tokens,
+            }));
+        }
+        "message_stop" => return (None, true),
+        // Errors reach the client as they are
+        "error" => return (Some(event), false),
+        _ => {}
+    }
+
+    if delta.is_empty() && finish_reason.is_none() && usage.is_none() {
+        return (None, false);
+    }
+    let mut translated = serde_json::json!({
+        "choices": [{ "delta": delta, "finish_reason": finish_reason }]
+    });
+    if let Some(usage) = usage {
+        translated["usage"] = usage;
+    }
+
+    (Some(SseEvent { event: None, id: event.id, data: translated.to_string() }), false)
+}
+
+/// One line of Ollama's native NDJSON stream (`/api/chat` or `/api/generate`).
+#[derive(Debug, Deserialize)]
+struct OllamaStreamChunk {
+    #[serde(default)]
+    message: Option<Message>,
+    /// `/api/generate` carries text here instead of in `message`.
+    #[serde(default)]
+    response: Option<String>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    done_reason: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
+}
+
+/// Rewrites an Ollama NDJSON object as an OpenAI-style delta, like
+/// `translate_gemini_event`. Returns true for the final (`done`) object.
+/// Objects that aren't chat chunks, such as `{"error": ...}`, pass through.
+fn translate_ollama_event(mut event: SseEvent) -> (SseEvent, bool) {
+    let Ok(chunk) = serde_json::from_str::<OllamaStreamChunk>(&event.data) else {
+        return (event, false);
+    };
+    if chunk.message.is_none() && chunk.response.is_none() && !chunk.done {
+        return (event, false);
+    }
+
+    let mut delta = serde_json::Map::new();
+    let (content, thinking, tool_calls) = match chunk.message {
+        Some(message) => (message.content, message.thinking, message.tool_calls),
+        None => (chunk.response, None, None),
+    };
+    if let Some(content) = content.filter(|c| !c.is_empty()) {
+        delta.insert("content".to_string(), content.into());
+    }
+    if let Some(thinking) = thinking.filter(|t| !t.is_empty()) {
+        delta.insert("reasoning_content".to_string(), thinking.into());
+    }
+    if let Some(tool_calls) = tool_calls {
+        delta.insert("tool_calls".to_string(), serde_json::to_value(tool_calls).unwrap_or_default());
+    }
+
+    let finish_reason = chunk
+        .done
+        .then(|| normalize_finish_reason(chunk.done_reason.as_deref().unwrap_or("stop")));
+    let mut translated = serde_json::json!({
+        "choices": [{ "delta": delta, "finish_reason": finish_reason }]
+    });
+    if chunk.done {
+        let prompt_tokens = chunk.prompt_eval_count.unwrap_or_default();
+        let completion_tokens = chunk.eval_count.unwrap_or_default();
+        translated["usage"] = serde_json::json!({
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        });
+    }
+
+    event.data = translated.to_string();
+    (event, chunk.done)
+}
+
+fn is_transient_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || (e.is_request() && !e.is_timeout())
+}
+
+/// Exponential backoff with up to 50% random jitter.
+fn retry_delay(attempt: u32) -> std::time::Duration {
+    let base = RETRY_BASE_DELAY_MS.saturating_mul(1 << attempt.min(6));
+    let jitter = (uuid::Uuid::new_v4().as_u128() % (base as u128 / 2 + 1)) as u64;
+    std::time::Duration::from_millis(base + jitter)
+}
+
+const RATE_LIMIT_HEADERS: &[&str] = &["retry-after", "retry-after-ms"];
+const RATE_LIMIT_HEADER_PREFIXES: &[&str] = &["x-ratelimit-", "anthropic-ratelimit-", "ratelimit"];
+
+/// Picks the headers a client can use to pace its requests.
+fn rate_limit_headers(headers: &reqwest::header::HeaderMap) -> HashMap<String, String> {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            RATE_LIMIT_HEADERS.contains(&name) || RATE_LIMIT_HEADER_PREFIXES.iter().any(|p| name.starts_with(p))
+        })
+        .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// Model aliases and default endpoints from `defaults` are applied before
+/// sending; anything they don't cover passes through unchanged.
+async fn proxy_chat_request(
+    client: reqwest::Client,
+    defaults: &ProviderDefaults,
+    api_endpoint: String,
+    api_key: Option<String>,
+    mut request: ChatRequest,
+    timeout_secs: u64,
+    max_retries: u32,
+) -> Result<ChatResult, ProxyError> {
+    validate_message_content(&request.messages)?;
+    let api_endpoint = defaults.endpoint_for(request.provider.as_deref(), api_endpoint);
+    let endpoint = normalize_endpoint(&api_endpoint)?;
+    let provider = resolve_provider(request.provider.as_deref(), endpoint.as_str())?;
+    request.model = defaults.resolve_model(provider, request.model);
+    let endpoint = match provider {
+        Provider::Gemini => gemini_url(endpoint, &request.model, false),
+        _ => endpoint,
+    };
+
+    let req_builder = client
+        .post(endpoint)
+        .timeout(std::time::Duration::from_secs(timeout_secs));
+
+    let req_builder = match provider {
+        Provider::Anthropic => add_anthropic_headers(req_builder, api_key),
+        Provider::Gemini => add_gemini_headers(req_builder, api_key),
+        Provider::OpenAi | Provider::Ollama => add_auth_headers(req_builder, api_key),
+    };
+    let req_builder = add_user_agent(req_builder, request.user_agent.as_deref());
+    let req_builder = add_custom_headers(req_builder, request.headers.as_ref(), request.override_content_type)?
+        .json(&build_chat_body(&request, provider));
+
+    // Connection failures and 5xx responses are retried; 4xx never are
+    let mut attempt = 0;
+    let response = loop {
+        let builder = req_builder
+            .try_clone()
+            .ok_or_else(|| "Failed to prepare request".to_string())?;
+
+        match builder.send().await {
+            Ok(response) if response.status().is_server_error() && attempt < max_retries => {
+                eprintln!("Upstream returned {}, retrying ({}/{})", response.status(), attempt + 1, max_retries);
+            }
+            Ok(response) => break response,
+            Err(e) if is_transient_error(&e) && attempt < max_retries => {
+                eprintln!("Request failed: {}, retrying ({}/{})", redact(&e.to_string()), attempt + 1, max_retries);
+            }
+            Err(e) => return Err(ProxyError::network(e)),
+        }
+
+        tokio::time::sleep(retry_delay(attempt)).await;
+        attempt += 1;
+    };
+
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+    let headers = rate_limit_headers(response.headers());
+
+    if provider == Provider::Gemini {
+        let response_data: GeminiResponse = response
+            .json()
+            .await
+            .map_err(ProxyError::parse)?;
+
+        return Ok(ChatResult {
+            content: response_data.text().1,
+            usage: response_data.usage(),
+            finish_reason: response_data.finish_reason(),
+            tool_calls: Vec::new(),
+            headers,
+        });
+    }
+
+    if provider == Provider::Anthropic {
+        let response_data: AnthropicResponse = response
+            .json()
+            .await
+            .map_err(ProxyError::parse)?;
+
+        let content = response_data
+            .content
+            .iter()
+            .filter(|block| block.kind == "text")
+            .filter_map(|block| block.text.as_deref())
+            .collect::<String>();
+
+        let tool_calls = response_data
+            .content
+            .iter()
+            .filter(|block| block.kind == "tool_use")
+            .map(|block| ToolCall {
+                id: block.id.clone(),
+                kind: default_tool_type(),
+                function: ToolFunction {
+                    name: block.name.clone().unwrap_or_default(),
+                    arguments: block.input.as_ref().map(|v| v.to_string()).unwrap_or_default(),
+                },
+            })
+            .collect();
+
+        return Ok(ChatResult {
+            content,
+            tool_calls,
+            headers,
+            usage: response_data.usage.map(|u| Usage {
+                prompt_tokens: u.input_tokens,
+                completion_tokens: u.output_tokens,
+                total_tokens: u.input_tokens + u.output_tokens,
+            }),
+            finish_reason: response_data.stop_reason.as_deref().map(normalize_finish_reason),
+        });
+    }
+
+    let response_data: ChatResponse = response
+        .json()
+        .await
+        .map_err(ProxyError::parse)?;
+    let finish_reason = response_data.finish_reason();
+
+    let message = match response_data.choices {
+        Some(choices) => choices.into_iter().next().and_then(|choice| choice.message),
+        None => response_data.message,
+    };
+    let (content, tool_calls) = match message {
+        Some(msg) => {
+            let mut tool_calls = ToolCallAccumulator::default();
+            tool_calls.push(msg.tool_calls.as_deref().unwrap_or_default());
+            (msg.content.unwrap_or_default(), tool_calls.finish())
+        }
+        None => (String::new(), Vec::new()),
+    };
+
+    Ok(ChatResult {
+        content,
+        usage: response_data.usage,
+        finish_reason,
+        tool_calls,
+        headers,
+    })
+}
+
+/// Picks the SSE event name for a `data:` payload, so reasoning tokens can be
+/// told apart from the answer. Returns `None` for payloads that aren't chat chunks.
+fn classify_stream_payload(payload: &str) -> Option<&'static str> {
+    let chunk: ChatResponse = serde_json::from_str(payload).ok()?;
+
+    let delta_reasoning = chunk
+        .choices
+        .as_ref()
+        .and_then(|choices| choices.first())
+        .and_then(|choice| choice.delta.as_ref())
+        .and_then(|d| d.reasoning_content.as_deref());
+    let message_thinking = chunk.message.as_ref().and_then(|m| m.thinking.as_deref());
+
+    let has_reasoning = delta_reasoning.or(message_thinking).is_some_and(|r| !r.is_empty());
+
+    if has_reasoning {
+        Some("reasoning")
+    } else {
+        Some("content")
+    }
+}
+
+/// A single server-sent event, with multi-line `data:` fields joined by `\n`.
+#[derive(Debug, Clone, PartialEq)]
+struct SseEvent {
+    event: Option<String>,
+    id: Option<String>,
+    data: String,
+}
+
+impl std::fmt::Display for SseEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", event)?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        for line in self.data.split('\n') {
+            writeln!(f, "data: {}", line)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Incremental SSE parser. Lines are fed one at a time and a complete event
+/// is returned at each blank line. Lines that aren't SSE fields (such as bare
+/// JSON from NDJSON streams) are treated as an event of their own.
+#[derive(Debug, Default)]
+struct SseParser {
+    event: Option<String>,
+    id: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    fn feed(&mut self, line: &str) -> Option<SseEvent> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        if line.trim().is_empty() {
+            return self.flush();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "data" => self.data.push(value.to_string()),
+            "event" => self.event = Some(value.to_string()),
+            "id" => self.id = Some(value.to_string()),
+            "retry" => {}
+            _ => {
+                self.data.push(line.trim().to_string());
+                return self.flush();
+            }
+        }
+
+        None
+    }
+
+    fn flush(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        let id = self.id.take();
+        if self.data.is_empty() {
+            return None;
+        }
+
+        Some(SseEvent {
+            event,
+            id,
+            data: std::mem::take(&mut self.data).join("\n"),
+        })
+    }
+}
+
+/// Formats an upstream event for the client, naming it after the payload kind
+/// when the upstream didn't. Returns true once the `[DONE]` sentinel is seen.
+fn render_stream_event(mut event: SseEvent) -> (String, bool) {
+    if event.data.trim() == "[DONE]" {
+        return ("data: [DONE]\n\n".to_string(), true);
+    }
+
+    let mut finish = None;
+
+    if event.event.is_none() {
+        event.event = classify_stream_payload(&event.data).map(str::to_string);
+        finish = serde_json::from_str::<ChatResponse>(&event.data)
+            .ok()
+            .and_then(|chunk| chunk.finish_reason());
+
+        #[cfg(debug_assertions)]
+        if event.event.is_none() {
+            eprintln!("Passing through SSE data: {}", redact(&event.data));
+        }
+    }
+
+    let mut text = event.to_string();
+
+    // Surface why generation stopped as its own event, e.g. so the UI can
+    // offer "continue" after "length"
+    if let Some(reason) = finish {
+        let finish_event = SseEvent {
+            event: Some("finish".to_string()),
+            id: None,
+            data: serde_json::json!({ "finish_reason": reason }).to_string(),
+        };
+        text.push_str(&finish_event.to_string());
+    }
+
+    (text, false)
+}
+
+/// Parses the chat chunks out of text produced by `proxy_chat_stream`.
+fn parse_stream_chunks(text: &str) -> impl Iterator<Item = ChatResponse> + '_ {
+    text.lines().filter_map(|line| {
+        let line = line.trim();
+        let payload = line.strip_prefix("data:").map(str::trim).unwrap_or(line);
+        if !payload.starts_with('{') {
+            return None;
+        }
+        serde_json::from_str::<ChatResponse>(payload).ok()
+    })
+}
+
+const STREAM_RESUME_ATTEMPTS: u32 = 3;
+
+/// Sends a streaming request, bounding only the wait for the response
+/// headers; the body may legitimately keep streaming for far longer.
+async fn send_stream_request(builder: reqwest::RequestBuilder, timeout_secs: u64) -> Result<reqwest::Response, ProxyError> {
+    let response = tokio::time::timeout(std::time::Duration::from_secs(timeout_secs), builder.send())
+        .await
+        .map_err(|_| {
+            ProxyError::new(
+                ErrorKind::Network,
+                format!("Request timed out after {}s waiting for a response", timeout_secs),
+            )
+        })?
+        .map_err(ProxyError::network)?;
+
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+    Ok(response)
+}
+
+/// Lines are split on raw bytes and only then decoded, so a character split
+/// across network chunks is reassembled, and invalid UTF-8 becomes U+FFFD
+/// instead of ending the stream.
+fn stream_body_reader(response: reqwest::Response) -> impl tokio::io::AsyncBufRead + Unpin {
+    use futures::TryStreamExt;
+
+    let reader = tokio_util::io::StreamReader::new(response.bytes_stream().map_err(std::io::Error::other));
+    tokio::io::BufReader::new(reader)
+}
+
+/// Upstreams that tag events with `id:` are resumed after a dropped
+/// connection by reconnecting with `Last-Event-ID`, a few times before the
+/// drop is reported. Streams without ids fail straight away.
+///
+/// SSE comment lines (`: ping` keep-alives) are dropped unless
+/// `forward_comments` is set, in which case each is yielded as its own
+/// chunk starting with ':'. Aliases are resolved as in `proxy_chat_request`.
+#[allow(clippy::too_many_arguments)]
+async fn proxy_chat_stream(
+    client: reqwest::Client,
+    defaults: &ProviderDefaults,
+    api_endpoint: String,
+    api_key: Option<String>,
+    mut request: ChatRequest,
+    timeout_secs: u64,
+    forward_comments: bool,
+) -> Result<(HashMap<String, String>, impl futures::Stream<Item = Result<String, std::io::Error>>), ProxyError> {
+    validate_message_content(&request.messages)?;
+    let api_endpoint = defaults.endpoint_for(request.provider.as_deref(), api_endpoint);
+    let endpoint = normalize_endpoint(&api_endpoint)?;
+    let provider = resolve_provider(request.provider.as_deref(), endpoint.as_str())?;
+    request.model = defaults.resolve_model(provider, request.model);
+    request.stream = true;
+    // The stream parser speaks the OpenAI/Ollama wire formats; Gemini and
+    // Anthropic chunks are translated into OpenAI deltas below
+    let endpoint = match provider {
+        Provider::Gemini => gemini_url(endpoint, &request.model, true),
+        _ => endpoint,
+    };
+
+    let mut req_builder = client.post(endpoint);
+
+    req_builder = match provider {
+        Provider::Anthropic => add_anthropic_headers(req_builder, api_key),
+        Provider::Gemini => add_gemini_headers(req_builder, api_key),
+        Provider::OpenAi | Provider::Ollama => add_auth_headers(req_builder, api_key),
+    };
+    req_builder = add_user_agent(req_builder, request.user_agent.as_deref());
+    req_builder = add_custom_headers(req_builder, request.headers.as_ref(), request.override_content_type)?;
+    let req_builder = req_builder.json(&build_chat_body(&request, provider));
+
+    let first = req_builder
+        .try_clone()
+        .ok_or_else(|| "Failed to prepare request".to_string())?;
+    let response = send_stream_request(first, timeout_secs).await?;
+
+    // Ollama's native routes stream NDJSON, which is normalized to the same
+    // OpenAI deltas as SSE upstreams
+    let ndjson = provider == Provider::Ollama
+        || response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.contains("ndjson"));
+    let headers = rate_limit_headers(response.headers());
+
+    let s = stream! {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut reader = stream_body_reader(response);
+        let mut buf = Vec::new();
+
+        let mut parser = SseParser::default();
+        let mut done = false;
+        let mut last_event_id: Option<String> = None;
+        let mut resume_attempts = 0;
+        let mut input_tokens = 0;
+
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    let resumed = match last_event_id.as_deref().filter(|_| !ndjson) {
+                        Some(id) => loop {
+                            if resume_attempts >= STREAM_RESUME_ATTEMPTS {
+                                break None;
+                            }
+                            tokio::time::sleep(retry_delay(resume_attempts)).await;
+                            resume_attempts += 1;
+                            eprintln!(
+                                "Stream dropped ({}), resuming after event {} ({}/{})",
+                                redact(&e.to_string()), id, resume_attempts, STREAM_RESUME_ATTEMPTS
+                            );
+
+                            let Some(builder) = req_builder.try_clone() else {
+                                break None;
+                            };
+                            match send_stream_request(builder.header("Last-Event-ID", id), timeout_secs).await {
+                                Ok(response) => break Some(response),
+                                Err(e) => eprintln!("Resume failed: {}", e),
+                            }
+                        },
+                        None => None,
+                    };
+
+                    match resumed {
+                        Some(response) => {
+                            // Whatever was left of the interrupted event is resent
+                            reader = stream_body_reader(response);
+                            parser = SseParser::default();
+                            continue;
+                        }
+                        None => {
+                            // A dropped connection must not look like a clean finish
+                            yield Err(std::io::Error::new(
+                                e.kind(),
+                                format!("Upstream connection dropped mid-stream: {}", e),
+                            ));
+                            return;
+                        }
+                    }
+                }
+            }
+            if buf.last() == Some(&b'\n') {
+                buf.pop();
+            }
+            let line = String::from_utf8_lossy(&buf);
+            if forward_comments && line.starts_with(':') {
+                yield Ok(format!("{}\n\n", line.trim_end_matches('\r')));
+                continue;
+            }
+
+            if let Some(mut event) = parser.feed(&line) {
+                if event.id.is_some() {
+                    last_event_id.clone_from(&event.id);
+                }
+                let mut ollama_done = false;
+                if provider == Provider::Anthropic {
+                    match translate_anthropic_event(event, &mut input_tokens) {
+                        (Some(translated), _) => event = translated,
+                        (None, true) => {
+                            yield Ok("data: [DONE]\n\n".to_string());
+                            done = true;
+                            break;
+                        }
+                        (None, false) => continue,
+                    }
+                } else if provider == Provider::Gemini {
+                    event = translate_gemini_event(event);
+                } else if ndjson {
+                    (event, ollama_done) = translate_ollama_event(event);
+                }
+                let (text, is_done) = render_stream_event(event);
+                yield Ok(text);
+                if ollama_done {
+                    yield Ok("data: [DONE]\n\n".to_string());
+                }
+                if is_done || ollama_done {
+                    done = true;
+                    break;
+                }
+            }
+        }
+
+        if !done {
+            let event = match parser.flush() {
+                Some(event) if provider == Provider::Anthropic => translate_anthropic_event(event, &mut input_tokens).0,
+                Some(event) if provider == Provider::Gemini => Some(translate_gemini_event(event)),
+                Some(event) if ndjson => Some(translate_ollama_event(event).0),
+                event => event,
+            };
+            if let Some(event) = event {
+                let (text, is_done) = render_stream_event(event);
+                yield Ok(text);
+                done = is_done;
+            }
+        }
+
+        // Some servers just close the connection when they're finished, so
+        // the sentinel is added for SSE consumers that wait for it
+        if !done {
+            yield Ok("data: [DONE]\n\n".to_string());
+        }
+    };
+
+    Ok((headers, s))
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Covers OpenAI (`data[].embedding`) and Ollama (`embedding`) responses.
+#[derive(Debug, Deserialize)]
+struct EmbeddingsResponse {
+    data: Option<Vec<EmbeddingData>>,
+    embedding: Option<Vec<f32>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmbeddingsApi {
+    OpenAi,
+    /// Ollama's `/api/embeddings` takes a single `prompt` per request.
+    Ollama,
+}
+
+/// Derives the embeddings URL from a chat endpoint, or uses it as-is if it
+/// already points at an embeddings route.
+fn embeddings_url(api_endpoint: &str) -> Result<(String, EmbeddingsApi), String> {
+    let url = normalize_endpoint(api_endpoint)?;
+    let path = url.path().trim_end_matches('/');
+    let base = &url[..url::Position::BeforePath];
+
+    if path.ends_with("/api/embeddings") {
+        return Ok((url.to_string(), EmbeddingsApi::Ollama));
+    }
+    if path.ends_with("/embeddings") {
+        return Ok((url.to_string(), EmbeddingsApi::OpenAi));
+    }
+    if path.starts_with("/api/") {
+        return Ok((format!("{}/api/embeddings", base), EmbeddingsApi::Ollama));
+    }
+
+    Ok((format!("{}/embeddings", openai_api_base(&url)), EmbeddingsApi::OpenAi))
+}
+
+/// `https://host/v1` for an OpenAI-style endpoint such as `.../v1/chat/completions`.
+fn openai_api_base(url: &url::Url) -> String {
+    let path = url.path().trim_end_matches('/');
+    let prefix = path.find("/v1").map(|i| &path[..i + 3]).unwrap_or("/v1");
+    format!("{}{}", &url[..url::Position::BeforePath], prefix)
+}
+
+async fn proxy_embeddings(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    input: Vec<String>,
+) -> Result<Vec<Vec<f32>>, ProxyError> {
+    let (url, api) = embeddings_url(&api_endpoint)?;
+
+    let bodies: Vec<serde_json::Value> = match api {
+        EmbeddingsApi::OpenAi => vec![serde_json::json!({ "model": model, "input": input })],
+        EmbeddingsApi::Ollama => input
+            .iter()
+            .map(|prompt| serde_json::json!({ "model": model, "prompt": prompt }))
+            .collect(),
+    };
+
+    let mut embeddings = Vec::with_capacity(input.len());
+
+    for body in bodies {
+        let mut req_builder = client
+            .post(&url)
+            .timeout(std::time::Duration::from_secs(DEFAULT_CHAT_TIMEOUT_SECS))
+            .header("Content-Type", "application/json");
+
+        req_builder = add_auth_headers(req_builder, api_key.clone());
+
+        let response = req_builder
+            .json(&body)
+            .send()
+            .await
+            .map_err(ProxyError::network)?;
+
+        if !response.status().is_success() {
+            return Err(ProxyError::from_response(response).await);
+        }
+
+        let data: EmbeddingsResponse = response
+            .json()
+            .await
+            .map_err(ProxyError::parse)?;
+
+        if let Some(mut items) = data.data {
+            items.sort_by_key(|item| item.index);
+            embeddings.extend(items.into_iter().map(|item| item.embedding));
+        } else if let Some(embedding) = data.embedding {
+            embeddings.push(embedding);
+        } else {
+            return Err(ProxyError::parse("no embeddings in response"));
+        }
+    }
+
+    Ok(embeddings)
+}
+
+const VALIDATE_TIMEOUT_SECS: u64 = 10;
+
+/// Outcome of `validate_chat_config`. `None` means the probe couldn't tell.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatConfigCheck {
+    pub reachable: bool,
+    pub auth_ok: Option<bool>,
+    pub model_found: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ProxyError>,
+}
+
+impl ChatConfigCheck {
+    fn from_error(error: ProxyError) -> Self {
+        let (reachable, auth_ok, model_found) = match (error.kind, error.status) {
+            (ErrorKind::Network | ErrorKind::Invalid, _) => (false, None, None),
+            (ErrorKind::Auth, _) => (true, Some(false), None),
+            (_, Some(404)) => (true, Some(true), Some(false)),
+            _ => (true, None, None),
+        };
+
+        Self { reachable, auth_ok, model_found, error: Some(error) }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiModelList {
+    data: Vec<OpenAiModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiModel {
+    id: String,
+}
+
+/// Ollama lists "llama3:latest" for a model requested as "llama3".
+fn model_matches(listed: &str, wanted: &str) -> bool {
+    listed == wanted || listed.strip_suffix(":latest") == Some(wanted)
+}
+
+async fn list_openai_models(
+    client: &reqwest::Client,
+    api_endpoint: &str,
+    api_key: Option<String>,
+) -> Result<Vec<String>, ProxyError> {
+    let url = normalize_endpoint(api_endpoint)?;
+    let models_url = format!("{}/models", openai_api_base(&url));
+
+    let response = add_auth_headers(client.get(&models_url), api_key)
+        .send()
+        .await
+        .map_err(ProxyError::network)?;
+
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+
+    let data: OpenAiModelList = response.json().await.map_err(ProxyError::parse)?;
+
+    Ok(data.data.into_iter().map(|m| m.id).collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStatus {
+    Valid,
+    /// The provider answered 401.
+    Invalid,
+    /// Network failure or any other response; see `http_status` and `error`.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKeyCheck {
+    pub status: KeyStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ProxyError>,
+}
+
+/// The cheapest authenticated call per provider: a models listing, which
+/// costs no tokens. Ollama has no auth of its own, so this only means
+/// something behind an authenticating gateway.
+fn api_key_probe(
+    client: &reqwest::Client,
+    provider: Provider,
+    url: &url::Url,
+    api_key: String,
+) -> reqwest::RequestBuilder {
+    let origin = &url[..url::Position::BeforePath];
+    match provider {
+        Provider::OpenAi => add_auth_headers(client.get(format!("{}/models", openai_api_base(url))), Some(api_key)),
+        Provider::Anthropic => add_anthropic_headers(client.get(format!("{}/v1/models?limit=1", origin)), Some(api_key)),
+        Provider::Gemini => add_gemini_headers(client.get(format!("{}/v1beta/models?pageSize=1", origin)), Some(api_key)),
+        Provider::Ollama => add_auth_headers(client.get(format!("{}/api/tags", origin)), Some(api_key)),
+    }
+}
+
+async fn proxy_validate_api_key(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: String,
+    provider: Option<String>,
+) -> Result<ApiKeyCheck, String> {
+    let url = normalize_endpoint(&api_endpoint)?;
+    let provider = resolve_provider(provider.as_deref(), url.as_str())?;
+
+    let response = api_key_probe(&client, provider, &url, api_key.clone())
+        .timeout(std::time::Duration::from_secs(VALIDATE_TIMEOUT_SECS))
+        .send()
+        .await;
+
+    // Errors are redacted already, but the key may not match a known pattern
+    let scrub = |mut error: ProxyError| {
+        error.message = error.message.replace(&api_key, "***");
+        error
+    };
+
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            return Ok(ApiKeyCheck {
+                status: KeyStatus::Unknown,
+                http_status: None,
+                error: Some(scrub(ProxyError::network(e))),
+            });
+        }
+    };
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(ApiKeyCheck { status: KeyStatus::Valid, http_status: Some(status.as_u16()), error: None });
+    }
+
+    Ok(ApiKeyCheck {
+        status: if status == reqwest::StatusCode::UNAUTHORIZED { KeyStatus::Invalid } else { KeyStatus::Unknown },
+        http_status: Some(status.as_u16()),
+        error: Some(scrub(ProxyError::from_response(response).await)),
+    })
+}
+
+/// Checks endpoint, key and model without a real completion: lists the
+/// models where the backend supports it, otherwise sends a 1-token request.
+async fn proxy_validate_chat_config(
+    client: reqwest::Client,
+    defaults: &ProviderDefaults,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    provider: Option<String>,
+) -> ChatConfigCheck {
+    let api_endpoint = defaults.endpoint_for(provider.as_deref(), api_endpoint);
+    let provider_kind = match resolve_provider(provider.as_deref(), &api_endpoint) {
+        Ok(provider_kind) => provider_kind,
+        Err(e) => return ChatConfigCheck::from_error(e.into()),
+    };
+    let model = defaults.resolve_model(provider_kind, model);
+
+    let listed = match provider_kind {
+        Provider::Ollama => proxy_scan_ollama_models(client.clone(), api_endpoint.clone(), api_key.clone(), None)
+            .await
+            .map(|models| models.into_iter().map(|m| m.name).collect::<Vec<_>>()),
+        Provider::OpenAi => list_openai_models(&client, &api_endpoint, api_key.clone()).await,
+        Provider::Anthropic | Provider::Gemini => Err(ProxyError::new(ErrorKind::Invalid, "model listing unsupported")),
+    };
+
+    match listed {
+        Ok(models) => {
+            return ChatConfigCheck {
+                reachable: true,
+                auth_ok: Some(true),
+                model_found: Some(models.iter().any(|m| model_matches(m, &model))),
+                error: None,
+            };
+        }
+        // Not every gateway implements a models route; fall back to a tiny chat
+        Err(e) if e.kind == ErrorKind::Invalid || matches!(e.status, Some(404 | 405)) => {}
+        Err(e) => return ChatConfigCheck::from_error(e),
+    }
+
+    let probe = ChatRequest {
+        model,
+        messages: vec![ChatMessage {
+            role: "user".to_string(),
+            content: serde_json::Value::String("ping".to_string()),
+            images: None,
+        }],
+        stream: false,
+        options: None,
+        max_tokens: Some(1),
+        temperature: None,
+        top_p: None,
+        keep_alive: None,
+        provider,
+        headers: None,
+        user_agent: None,
+        override_content_type: false,
+    };
+
+    match proxy_chat_request(client, defaults, api_endpoint, api_key, probe, VALIDATE_TIMEOUT_SECS, 0).await {
+        Ok(_) => ChatConfigCheck {
+            reachable: true,
+            auth_ok: Some(true),
+            model_found: Some(true),
+            error: None,
+        },
+        Err(e) => ChatConfigCheck::from_error(e),
+    }
+}
+
+const DEFAULT_LATENCY_SAMPLES: u32 = 3;
+const MAX_LATENCY_SAMPLES: u32 = 20;
+const LATENCY_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyStats {
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    pub samples: u32,
+    /// "HEAD", or "GET" when the endpoint rejected HEAD.
+    pub method: &'static str,
+}
+
+/// Times `samples` small requests to the endpoint's origin. The first one
+/// usually includes connection setup; later ones reuse the pooled connection.
+async fn proxy_measure_endpoint_latency(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    samples: u32,
+) -> Result<LatencyStats, ProxyError> {
+    let url = normalize_endpoint(&api_endpoint)?;
+    let base_url = format!("{}/", &url[..url::Position::BeforePath]);
+    let tags_url = ollama_api_url(&api_endpoint, "/api/tags")?;
+    let timeout = std::time::Duration::from_secs(LATENCY_TIMEOUT_SECS);
+
+    let mut method = "HEAD";
+    let mut timings = Vec::with_capacity(samples as usize);
+
+    while timings.len() < samples as usize {
+        let req_builder = match method {
+            "HEAD" => client.head(&base_url),
+            _ => client.get(&tags_url),
+        };
+        let req_builder = add_auth_headers(req_builder.timeout(timeout), api_key.clone());
+
+        let started = std::time::Instant::now();
+        let result = req_builder.send().await;
+        let elapsed = started.elapsed();
+
+        match result {
+            Ok(response) => {
+                let status = response.status();
+                // Any other status still measures a round trip; servers that
+                // reject HEAD are sampled over GET /api/tags instead
+                if method == "HEAD" && (status == reqwest::StatusCode::METHOD_NOT_ALLOWED || status == reqwest::StatusCode::NOT_IMPLEMENTED) {
+                    method = "GET";
+                    timings.clear();
+                    continue;
+                }
+                timings.push(elapsed.as_secs_f64() * 1000.0);
+            }
+            Err(e) if method == "HEAD" && !e.is_connect() && !e.is_timeout() => {
+                method = "GET";
+                timings.clear();
+            }
+            Err(e) if e.is_timeout() => {
+                return Err(ProxyError::new(
+                    ErrorKind::Network,
+                    format!("Endpoint did not respond within {}s", LATENCY_TIMEOUT_SECS),
+                ));
+            }
+            Err(e) => return Err(ProxyError::network(e)),
+        }
+    }
+
+    let min_ms = timings.iter().copied().fold(f64::INFINITY, f64::min);
+    let max_ms = timings.iter().copied().fold(0.0, f64::max);
+    let avg_ms = timings.iter().sum::<f64>() / timings.len() as f64;
+
+    Ok(LatencyStats { min_ms, avg_ms, max_ms, samples, method })
+}
+
+const BENCHMARK_PROMPT: &str = "Count upward from 1, one number per line, without any other text. Keep going until you are told to stop.";
+const DEFAULT_BENCHMARK_MAX_TOKENS: u64 = 256;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchmarkResult {
+    /// Time until the first content chunk; `None` if nothing arrived.
+    pub time_to_first_token_ms: Option<u64>,
+    pub total_ms: u64,
+    /// Completion token count, from upstream usage when reported, otherwise
+    /// the number of content chunks.
+    pub tokens: u64,
+    pub chunks: u64,
+    pub usage_reported: bool,
+    /// Tokens per second after the first token, so load time doesn't skew it.
+    pub tokens_per_second: f64,
+    pub cancelled: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn proxy_benchmark_model(
+    client: reqwest::Client,
+    defaults: &ProviderDefaults,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    provider: Option<String>,
+    max_tokens: u64,
+    timeout_secs: u64,
+    token: &CancellationToken,
+) -> Result<BenchmarkResult, ProxyError> {
+    use futures::StreamExt;
+
+    let request = ChatRequest {
+        model,
+        messages: vec![ChatMessage {
+            role: "user".to_string(),
+            content: serde_json::Value::String(BENCHMARK_PROMPT.to_string()),
+            images: None,
+        }],
+        stream: true,
+        options: None,
+        max_tokens: Some(max_tokens),
+        temperature: Some(0.0),
+        top_p: None,
+        keep_alive: None,
+        provider,
+        headers: None,
+        user_agent: None,
+        override_content_type: false,
+    };
+
+    let started = std::time::Instant::now();
+    let mut first_token = None;
+    let mut chunks = 0;
+    let mut usage = None;
+
+    let stream = tokio::select! {
+        _ = token.cancelled() => None,
+        stream = proxy_chat_stream(client, defaults, api_endpoint, api_key, request, timeout_secs, false) => Some(stream?.1),
+    };
+
+    if let Some(stream) = stream {
+        tokio::pin!(stream);
+
+        loop {
+            let chunk_result = tokio::select! {
+                _ = token.cancelled() => break,
+                chunk_result = stream.next() => chunk_result,
+            };
+
+            match chunk_result {
+                Some(Ok(chunk)) => {
+                    for parsed in parse_stream_chunks(&chunk) {
+                        if chunk_content(&parsed).is_some_and(|text| !text.is_empty()) {
+                            first_token.get_or_insert_with(|| started.elapsed());
+                            chunks += 1;
+                        }
+                        if parsed.usage.is_some() {
+                            usage = parsed.usage;
+                        }
+                    }
+                }
+                Some(Err(e)) => return Err(ProxyError::new(ErrorKind::Network, format!("Stream error: {}", e))),
+                None => break,
+            }
+        }
+    }
+
+    let total = started.elapsed();
+    let tokens = usage.as_ref().map(|u| u.completion_tokens).unwrap_or(chunks);
+    let tokens_per_second = match first_token {
+        Some(first) if tokens > 1 && total > first => (tokens - 1) as f64 / (total - first).as_secs_f64(),
+        _ => 0.0,
+    };
+
+    Ok(BenchmarkResult {
+        time_to_first_token_ms: first_token.map(|d| d.as_millis() as u64),
+        total_ms: total.as_millis() as u64,
+        tokens,
+        chunks,
+        usage_reported: usage.is_some(),
+        tokens_per_second,
+        cancelled: token.is_cancelled(),
+    })
+}
+
+const DEFAULT_MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+const DEFAULT_MAX_TOTAL_IMAGE_BYTES: usize = 50 * 1024 * 1024;
+
+/// Size limits for `ChatMessage::images`, measured on the decoded bytes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ImageLimits {
+    pub max_image_bytes: usize,
+    pub max_total_bytes: usize,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_image_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            max_total_bytes: DEFAULT_MAX_TOTAL_IMAGE_BYTES,
+        }
+    }
+}
+
+/// Strips an optional `data:<mime>;base64,` prefix and decodes the payload.
+fn decode_image_payload(image: &str) -> Result<Vec<u8>, String> {
+    use base64::Engine;
+
+    let payload = match image.strip_prefix("data:") {
+        Some(rest) => {
+            let (meta, data) = rest
+                .split_once(',')
+                .ok_or_else(|| "malformed data URL".to_string())?;
+            if !meta.ends_with(";base64") {
+                return Err("data URL is not base64-encoded".to_string());
+            }
+            data
+        }
+        None => image,
+    };
+
+    let cleaned: String = payload.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(cleaned)
+        .map_err(|e| format!("invalid base64: {}", e))
+}
+
+fn validate_images(messages: &[ChatMessage], limits: &ImageLimits) -> Result<(), String> {
+    let mut total = 0usize;
+
+    for (message_index, message) in messages.iter().enumerate() {
+        let Some(images) = &message.images else {
+            continue;
+        };
+
+        for (image_index, image) in images.iter().enumerate() {
+            let bytes = decode_image_payload(image).map_err(|e| {
+                format!("Image {} in message {} is invalid: {}", image_index + 1, message_index + 1, e)
+            })?;
+
+            if bytes.len() > limits.max_image_bytes {
+                return Err(format!(
+                    "Image {} in message {} is {} bytes, exceeding the {} byte limit",
+                    image_index + 1,
+                    message_index + 1,
+                    bytes.len(),
+                    limits.max_image_bytes
+                ));
+            }
+
+            total += bytes.len();
+            if total > limits.max_total_bytes {
+                return Err(format!(
+                    "Images exceed the total limit of {} bytes at image {} in message {}",
+                    limits.max_total_bytes,
+                    image_index + 1,
+                    message_index + 1
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Text carried by a streamed chunk, for both OpenAI deltas and Ollama messages.
+fn chunk_content(chunk: &ChatResponse) -> Option<&str> {
+    if let Some(choice) = chunk.choices.as_ref().and_then(|choices| choices.first()) {
+        return choice
+            .delta
+            .as_ref()
+            .and_then(|d| d.content.as_deref())
+            .or_else(|| choice.message.as_ref().and_then(|m| m.content.as_deref()));
+    }
+    chunk.message.as_ref().and_then(|m| m.content.as_deref())
+}
+
+fn chunk_tool_calls(chunk: &ChatResponse) -> &[ToolCallDelta] {
+    let tool_calls = match chunk.choices.as_ref().and_then(|choices| choices.first()) {
+        Some(choice) => choice
+            .delta
+            .as_ref()
+            .and_then(|d| d.tool_calls.as_deref())
+            .or_else(|| choice.message.as_ref().and_then(|m| m.tool_calls.as_deref())),
+        None => chunk.message.as_ref().and_then(|m| m.tool_calls.as_deref()),
+    };
+    tool_calls.unwrap_or_default()
+}
+
+/// Runs a chat request through the streaming path and buffers the answer, so
+/// callers that want a single result can still cancel mid-generation. On
+/// cancellation the content received so far is returned.
+async fn collect_chat_stream(
+    client: reqwest::Client,
+    defaults: &ProviderDefaults,
+    api_endpoint: String,
+    api_key: Option<String>,
+    mut request: ChatRequest,
+    timeout_secs: u64,
+    token: &CancellationToken,
+) -> Result<ChatResult, ProxyError> {
+    use futures::StreamExt;
+
+    request.stream = true;
+
+    let stream = tokio::select! {
+        _ = token.cancelled() => {
+            return Ok(ChatResult {
+                content: String::new(),
+                usage: None,
+                finish_reason: None,
+                tool_calls: Vec::new(),
+                headers: HashMap::new(),
+            });
+        }
+        stream = proxy_chat_stream(client, defaults, api_endpoint, api_key, request, timeout_secs, false) => stream?,
+    };
+    let (headers, stream) = stream;
+
+    tokio::pin!(stream);
+
+    let mut content = String::new();
+    let mut usage = None;
+    let mut finish_reason = None;
+    let mut tool_calls = ToolCallAccumulator::default();
+
+    loop {
+        let chunk_result = tokio::select! {
+            _ = token.cancelled() => break,
+            chunk_result = stream.next() => chunk_result,
+        };
+
+        match chunk_result {
+            Some(Ok(chunk)) => {
+                for parsed in parse_stream_chunks(&chunk) {
+                    if let Some(text) = chunk_content(&parsed) {
+                        content.push_str(text);
+                    }
+                    tool_calls.push(chunk_tool_calls(&parsed));
+                    if let Some(reason) = parsed.finish_reason() {
+                        finish_reason = Some(reason);
+                    }
+                    if parsed.usage.is_some() {
+                        usage = parsed.usage;
+                    }
+                }
+            }
+            Some(Err(e)) => return Err(ProxyError::new(ErrorKind::Network, format!("Stream error: {}", e))),
+            None => break,
+        }
+    }
+
+    Ok(ChatResult { content, usage, finish_reason, tool_calls: tool_calls.finish(), headers })
+}
+
+const IDEMPOTENCY_TTL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// A chat result and whether it is complete enough to replay; cancelled
+/// requests return partial content that must not be cached.
+type SharedChat = futures::future::Shared<futures::future::BoxFuture<'static, (Result<ChatResult, ProxyError>, bool)>>;
+
+enum IdempotentEntry {
+    InFlight(SharedChat),
+    Done {
+        at: std::time::Instant,
+        result: ChatResult,
+    },
+}
+
+/// Results of `send_chat_request` calls made with an idempotency key.
+#[derive(Default)]
+struct IdempotencyCache(Mutex<HashMap<String, IdempotentEntry>>);
+
+impl IdempotencyCache {
+    /// Runs `send` unless `key` was used within the TTL, in which case the
+    /// earlier result is returned, or the earlier request joined if it's
+    /// still running. Failures aren't cached, so a retry sends again.
+    async fn run(
+        &self,
+        key: String,
+        send: futures::future::BoxFuture<'static, (Result<ChatResult, ProxyError>, bool)>,
+    ) -> Result<ChatResult, ProxyError> {
+        use futures::FutureExt;
+
+        let shared = {
+            let mut map = self.0.lock().map_err(|e| format!("Lock error: {}", e))?;
+            map.retain(|_, entry| !matches!(entry, IdempotentEntry::Done { at, .. } if at.elapsed() >= IDEMPOTENCY_TTL));
+            match map.get(&key) {
+                Some(IdempotentEntry::Done { result, .. }) => return Ok(result.clone()),
+                Some(IdempotentEntry::InFlight(shared)) => shared.clone(),
+                None => {
+                    let shared = send.shared();
+                    map.insert(key.clone(), IdempotentEntry::InFlight(shared.clone()));
+                    shared
+                }
+            }
+        };
+
+        let (result, complete) = shared.clone().await;
+
+        let mut map = self.0.lock().map_err(|e| format!("Lock error: {}", e))?;
+        if matches!(map.get(&key), Some(IdempotentEntry::InFlight(current)) if current.ptr_eq(&shared)) {
+            match &result {
+                Ok(chat) if complete => {
+                    map.insert(key, IdempotentEntry::Done { at: std::time::Instant::now(), result: chat.clone() });
+                }
+                _ => {
+                    map.remove(&key);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// With an `idempotency_key`, a repeat of the same key within ten minutes
+/// returns the first result instead of generating (and billing) it again.
+/// The key is also sent upstream as `Idempotency-Key` for providers that
+/// honour it.
+///
+/// A `stream_id` makes the call cancellable. It is then streamed, so a
+/// cancel keeps the partial answer, unless `max_retries` is also given:
+/// retries need the plain request, which a cancel abandons without content.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn send_chat_request(
+    api_endpoint: String,
+    api_key: Option<String>,
+    mut request: ChatRequest,
+    timeout_secs: Option<u64>,
+    max_retries: Option<u32>,
+    image_limits: Option<ImageLimits>,
+    stream_id: Option<String>,
+    idempotency_key: Option<String>,
+    connect_timeout_secs: Option<u64>,
+    http: tauri::State<'_, HttpState>,
+    token_map: tauri::State<'_, TokenMap>,
+    idempotency: tauri::State<'_, IdempotencyCache>,
+) -> Result<ChatResult, ProxyError> {
+    use futures::FutureExt;
+
+    validate_images(&request.messages, &image_limits.unwrap_or_default())?;
+    let timeout_secs = resolve_timeout(timeout_secs)?;
+    let client = http.client_for(connect_timeout_secs)?;
+    let idempotency_key = idempotency_key.map(|key| key.trim().to_string()).filter(|key| !key.is_empty());
+
+    if let Some(key) = &idempotency_key {
+        let headers = request.headers.get_or_insert_with(HashMap::new);
+        if !headers.keys().any(|name| name.eq_ignore_ascii_case("idempotency-key")) {
+            headers.insert("Idempotency-Key".to_string(), key.clone());
+        }
+    }
+
+    // Store stream context for cancellation. Without a stream_id there is
+    // nothing to cancel, so the plain request is used
+    let token = match &stream_id {
+        Some(stream_id) => Some(register_stream(&token_map, stream_id)?.token),
+        None => None,
+    };
+
+    let defaults = http.provider_defaults();
+    let cache_key = idempotency_key.map(|key| format!("{}\n{}", api_endpoint, key));
+    let send = async move {
+        match (token, max_retries) {
+            (Some(token), None) => {
+                let result = collect_chat_stream(client, &defaults, api_endpoint, api_key, request, timeout_secs, &token).await;
+                (result, !token.is_cancelled())
+            }
+            (Some(token), Some(max_retries)) => {
+                let request = proxy_chat_request(client, &defaults, api_endpoint, api_key, request, timeout_secs, max_retries);
+                tokio::select! {
+                    _ = token.cancelled() => (Ok(ChatResult {
+                        content: String::new(),
+                        usage: None,
+                        finish_reason: None,
+                        tool_calls: Vec::new(),
+                        headers: HashMap::new(),
+                    }), false),
+                    result = request => (result, true),
+                }
+            }
+            (None, max_retries) => {
+                let max_retries = max_retries.unwrap_or(DEFAULT_MAX_RETRIES);
+                (proxy_chat_request(client, &defaults, api_endpoint, api_key, request, timeout_secs, max_retries).await, true)
+            }
+        }
+    }
+    .boxed();
+
+    let result = match cache_key {
+        Some(key) => idempotency.run(key, send).await,
+        None => send.await.0,
+    };
+
+    // Clean up
+    if let Some(stream_id) = &stream_id {
+        let mut map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+        map.remove(stream_id);
+    }
+
+    result
+}
+
+const MODERATION_CONFIG_KEY: &str = "moderation";
+const DEFAULT_MODERATION_REPLACEMENT: &str = "[filtered]";
+
+/// Blocklist applied to streamed replies. Off unless enabled.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModerationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Regular expressions, matched as written.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Plain words, matched case-insensitively on word boundaries.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Replace matches instead of only reporting them.
+    #[serde(default)]
+    pub redact: bool,
+    #[serde(default)]
+    pub replacement: Option<String>,
+}
+
+/// Sent as `stream-flagged-{id}`. The matched text itself is never emitted.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamFlagged {
+    pub matches: usize,
+    pub redacted: bool,
+    /// Set for matches that only show up once chunks are joined, which
+    /// can't be removed from text that was already emitted.
+    pub final_message: bool,
+}
+
+struct ContentFilter {
+    blocklist: regex::Regex,
+    redact: bool,
+    replacement: String,
+}
+
+impl ContentFilter {
+    fn from_config(config: &ModerationConfig) -> Result<Option<Self>, String> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let mut parts = Vec::new();
+        for pattern in &config.patterns {
+            regex::Regex::new(pattern).map_err(|e| format!("Invalid moderation pattern '{}': {}", pattern, e))?;
+            parts.push(format!("(?:{})", pattern));
+        }
+        for keyword in config.keywords.iter().map(|k| k.trim()).filter(|k| !k.is_empty()) {
+            parts.push(format!(r"(?i:\b{}\b)", regex::escape(keyword)));
+        }
+        if parts.is_empty() {
+            return Ok(None);
+        }
+
+        let blocklist = regex::Regex::new(&parts.join("|")).map_err(|e| format!("Invalid moderation blocklist: {}", e))?;
+        Ok(Some(Self {
+            blocklist,
+            redact: config.redact,
+            replacement: config
+                .replacement
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MODERATION_REPLACEMENT.to_string()),
+        }))
+    }
+
+    /// Counts matches in `text`, replacing them when redacting.
+    fn apply(&self, text: &mut String) -> usize {
+        let matches = self.blocklist.find_iter(text).count();
+        if matches > 0 && self.redact {
+            *text = self
+                .blocklist
+                .replace_all(text, regex::NoExpand(&self.replacement))
+                .into_owned();
+        }
+        matches
+    }
+
+    /// Filters the reply text inside a rendered stream chunk, leaving the
+    /// rest of the SSE framing untouched.
+    fn apply_to_chunk(&self, chunk: &mut String) -> usize {
+        let mut matches = 0;
+        let mut filtered = String::with_capacity(chunk.len());
+
+        for line in chunk.split_inclusive('\n') {
+            let body = line.trim_end_matches(['\r', '\n']);
+            let payload = body.strip_prefix("data:").map(str::trim).unwrap_or_default();
+            let mut value = match payload.starts_with('{').then(|| serde_json::from_str::<serde_json::Value>(payload)) {
+                Some(Ok(value)) => value,
+                _ => {
+                    filtered.push_str(line);
+                    continue;
+                }
+            };
+
+            let mut line_matches = 0;
+            for field in content_fields(&mut value) {
+                if let serde_json::Value::String(text) = field {
+                    line_matches += self.apply(text);
+                }
+            }
+            matches += line_matches;
+
+            if line_matches > 0 && self.redact {
+                filtered.push_str("data: ");
+                filtered.push_str(&value.to_string());
+                filtered.push_str(&line[body.len()..]);
+            } else {
+                filtered.push_str(line);
+            }
+        }
+
+        if matches > 0 && self.redact {
+            *chunk = filtered;
+        }
+        matches
+    }
+}
+
+/// The reply text fields of an OpenAI or Ollama style chunk.
+fn content_fields(value: &mut serde_json::Value) -> Vec<&mut serde_json::Value> {
+    let mut fields = Vec::new();
+    let Some(object) = value.as_object_mut() else {
+        return fields;
+    };
+
+    for (key, value) in object.iter_mut() {
+        match (key.as_str(), value) {
+            ("choices", serde_json::Value::Array(choices)) => {
+                for choice in choices.iter_mut().filter_map(serde_json::Value::as_object_mut) {
+                    for (key, value) in choice.iter_mut() {
+                        if key == "delta" || key == "message" {
+                            fields.extend(value.get_mut("content"));
+                        }
+                    }
+                }
+            }
+            ("message", value) => fields.extend(value.get_mut("content")),
+            _ => {}
+        }
+    }
+    fields
+}
+
+fn load_moderation_config(app: &tauri::AppHandle) -> ModerationConfig {
+    app.store(STORE_FILE)
+        .ok()
+        .and_then(|store| store.get(MODERATION_CONFIG_KEY))
+        .and_then(|value| serde_json::from_value(value).ok())
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+async fn get_moderation_config(app: tauri::AppHandle) -> Result<ModerationConfig, String> {
+    Ok(load_moderation_config(&app))
+}
+
+/// Saves the blocklist for later streams, rejecting invalid patterns.
+#[tauri::command]
+async fn set_moderation_config(app: tauri::AppHandle, config: ModerationConfig) -> Result<(), String> {
+    ContentFilter::from_config(&ModerationConfig { enabled: true, ..config.clone() })?;
+
+    let store = app.store(STORE_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(&config).map_err(|e| e.to_string())?;
+    store.set(MODERATION_CONFIG_KEY, value);
+    store.save().map_err(|e| e.to_string())
+}
+
+/// Sent as `stream-json-valid-{id}` when `validate_json` is set.
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonValidation {
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// 1-based position of the parse error within the content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+/// Checks a finished JSON-mode reply. A surrounding ```json fence, which
+/// models add even in JSON mode, is ignored; error positions then refer to
+/// the fenced body.
+fn validate_json_content(content: &str) -> JsonValidation {
+    let trimmed = content.trim();
+    let body = trimmed
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map(|fenced| fenced.strip_prefix("json").unwrap_or(fenced))
+        .unwrap_or(trimmed);
+
+    match serde_json::from_str::<serde::de::IgnoredAny>(body) {
+        Ok(_) => JsonValidation { valid: true, error: None, line: None, column: None },
+        Err(e) => JsonValidation {
+            valid: false,
+            error: Some(e.to_string()),
+            line: Some(e.line()),
+            column: Some(e.column()),
+        },
+    }
+}
+
+/// A line that opens or closes a fenced code block: its fence character,
+/// run length and whatever follows the run.
+fn fence_marker(line: &str) -> Option<(char, usize, &str)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let run = trimmed.chars().take_while(|c| *c == marker).count();
+    let rest = &trimmed[run..];
+    // Backtick fences can't have backticks in their info string
+    (run >= 3 && !(marker == '`' && rest.contains('`'))).then_some((marker, run, rest))
+}
+
+/// Closes a dangling code fence. With `cut_off`, meaning the text is known
+/// to have stopped mid-reply, an inline code span left open on the last line
+/// is closed too; otherwise a lone backtick there is valid Markdown and kept.
+fn repair_markdown_text(text: &str, cut_off: bool) -> String {
+    let mut fence: Option<(char, usize)> = None;
+    let mut last_line_is_fence = false;
+    for line in text.lines() {
+        let marker = fence_marker(line);
+        last_line_is_fence = marker.is_some();
+        match (fence, marker) {
+            (None, Some((marker, run, _))) => fence = Some((marker, run)),
+            (Some((open, len)), Some((marker, run, rest))) if marker == open && run >= len && rest.trim().is_empty() => {
+                fence = None
+            }
+            _ => {}
+        }
+    }
+
+    let mut repaired = text.to_string();
+    if let Some((marker, len)) = fence {
+        if !repaired.ends_with('\n') {
+            repaired.push('\n');
+        }
+        repaired.extend(std::iter::repeat_n(marker, len));
+        return repaired;
+    }
+
+    // A reply that ends on a newline finished its line, so any lone
+    // backtick there is meant literally
+    let last_line = text.rsplit('\n').next().unwrap_or_default();
+    if !cut_off || text.ends_with('\n') || last_line_is_fence || last_line.starts_with("    ") {
+        return repaired;
+    }
+    let mut open_span = None;
+    let mut chars = last_line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '`' {
+            continue;
+        }
+        let mut run = 1;
+        while chars.next_if_eq(&'`').is_some() {
+            run += 1;
+        }
+        open_span = match open_span {
+            Some(open) if open == run => None,
+            None => Some(run),
+            open => open,
+        };
+    }
+    if let Some(run) = open_span {
+        repaired.extend(std::iter::repeat_n('`', run));
+    }
+    repaired
+}
+
+/// See `repair_markdown_text`; `cut_off` defaults to false.
+#[tauri::command]
+async fn repair_markdown(text: String, cut_off: Option<bool>) -> Result<String, String> {
+    Ok(repair_markdown_text(&text, cut_off.unwrap_or(false)))
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn send_chat_stream(
+    api_endpoint: String,
+    api_key: Option<String>,
+    request: ChatRequest,
+    window: tauri::Window,
+    stream_id: Option<String>,
+    timeout_secs: Option<u64>,
+    idle_timeout_secs: Option<u64>,
+    image_limits: Option<ImageLimits>,
+    batch_interval_ms: Option<u64>,
+    batch_max_bytes: Option<usize>,
+    validate_json: Option<bool>,
+    forward_keep_alive: Option<bool>,
+    max_content_bytes: Option<usize>,
+    connect_timeout_secs: Option<u64>,
+    repair_partial_markdown: Option<bool>,
+    http: tauri::State<'_, HttpState>,
+) -> Result<String, ProxyError> {
+    validate_images(&request.messages, &image_limits.unwrap_or_default())?;
+    let max_content_bytes = match max_content_bytes {
+        Some(0) => return Err("Invalid max_content_bytes: must be greater than 0".to_string().into()),
+        Some(n) => n,
+        None => usize::MAX,
+    };
+    let timeout_secs = resolve_timeout(timeout_secs)?;
+    let client = http.client_for(connect_timeout_secs)?;
+    let idle_timeout_secs = match idle_timeout_secs {
+        Some(0) => return Err("Invalid timeout: idle_timeout_secs must be greater than 0".to_string().into()),
+        Some(secs) => secs,
+        None => DEFAULT_STREAM_IDLE_TIMEOUT_SECS,
+    };
+    let idle_timeout = std::time::Duration::from_secs(idle_timeout_secs);
+    // Without either batch setting every chunk is emitted as it arrives
+    let batch_interval = (batch_interval_ms.is_some() || batch_max_bytes.is_some()).then(|| {
+        std::time::Duration::from_millis(batch_interval_ms.unwrap_or(DEFAULT_STREAM_BATCH_INTERVAL_MS))
+    });
+    let batch_max_bytes = batch_max_bytes.unwrap_or(usize::MAX);
+    let filter = ContentFilter::from_config(&load_moderation_config(window.app_handle()))?;
+    let stream_id = stream_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    // Store stream context for cancellation
+    let token_map = window.state::<TokenMap>();
+    let StreamContext { token, paused } = register_stream(&token_map, &stream_id)?;
+    let mut paused = paused.subscribe();
+
+    let forward_keep_alive = forward_keep_alive.unwrap_or(false);
+    let stream = match proxy_chat_stream(client, &http.provider_defaults(), api_endpoint, api_key, request, timeout_secs, forward_keep_alive).await {
+        Ok((headers, stream)) => {
+            let _ = window.emit(&format!("stream-headers-{}", stream_id), &headers);
+            stream
+        }
+        Err(e) => {
+            if let Ok(mut map) = token_map.lock() {
+                map.remove(&stream_id);
+            }
+            return Err(e);
+        }
+    };
+
+    let mut total_bytes = 0;
+    let mut content = String::new();
+    let mut truncated = false;
+    let memory = window.state::<StreamMemory>();
+    let mut memory = StreamMemoryGuard::new(&memory);
+    let mut role = None;
+    let mut finish_reason = None;
+    let mut tool_calls = ToolCallAccumulator::default();
+    use futures::StreamExt;
+
+    tokio::pin!(stream);
+
+    let started = tokio::time::Instant::now();
+    let mut last_chunk = started;
+    let mut heartbeat = tokio::time::interval_at(started + STREAM_HEARTBEAT_INTERVAL, STREAM_HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut pending = String::new();
+    let mut flush_at = None;
+    let mut flagged = 0;
+    let mut timed_out = false;
+
+    loop {
+        // While paused the upstream isn't read at all, so it backs up and
+        // may eventually drop the connection, which surfaces on resume
+        let is_paused = *paused.borrow_and_update();
+        let chunk_result = tokio::select! {
+            next = stream.next(), if !is_paused => match next {
+                Some(chunk_result) => chunk_result,
+                None => break,
+            },
+            _ = token.cancelled() => break,
+            changed = paused.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                // The idle timer only counts time spent waiting while running
+                last_chunk = tokio::time::Instant::now();
+                continue;
+            }
+            _ = tokio::time::sleep_until(flush_at.unwrap_or(last_chunk)), if flush_at.is_some() => {
+                flush_at = None;
+                flush_stream_batch(&window, &stream_id, &mut pending);
+                continue;
+            }
+            _ = heartbeat.tick() => {
+                let _ = window.emit(&format!("stream-heartbeat-{}", stream_id), StreamHeartbeat {
+                    elapsed_ms: started.elapsed().as_millis() as u64,
+                    total_bytes,
+                    paused: is_paused,
+                });
+                continue;
+            }
+            // The deadline moves with every chunk, so only a stalled upstream trips it
+            _ = tokio::time::sleep_until(last_chunk + idle_timeout), if !is_paused => {
+                timed_out = true;
+                flush_stream_batch(&window, &stream_id, &mut pending);
+                let message = format!("Stream timed out: no data received for {}s", idle_timeout_secs);
+                eprintln!("{}", message);
+                let _ = window.emit(&format!("stream-error-{}", stream_id), &message);
+                break;
+            }
+        };
+        last_chunk = tokio::time::Instant::now();
+
+        match chunk_result {
+            // Upstream keep-alive, and proof the connection is still up
+            Ok(chunk) if chunk.starts_with(':') => {
+                let comment = chunk.trim_start_matches(':').trim();
+                let _ = window.emit(&format!("stream-keep-alive-{}", stream_id), comment);
+            }
+            Ok(mut chunk) => {
+                if let Some(filter) = &filter {
+                    let matches = filter.apply_to_chunk(&mut chunk);
+                    if matches > 0 {
+                        flagged += matches;
+                        let _ = window.emit(&format!("stream-flagged-{}", stream_id), StreamFlagged {
+                            matches,
+                            redacted: filter.redact,
+                            final_message: false,
+                        });
+                    }
+                }
+
+                total_bytes += chunk.len();
+                for parsed in parse_stream_chunks(&chunk) {
+                    if role.is_none() {
+                        role = parsed.role().map(str::to_string);
+                    }
+                    if let Some(text) = chunk_content(&parsed).filter(|_| !truncated) {
+                        let (added, cut) = push_capped(&mut content, text, max_content_bytes);
+                        memory.grow(added);
+                        truncated = cut;
+                    }
+                    tool_calls.push(chunk_tool_calls(&parsed));
+                    if let Some(reason) = parsed.finish_reason() {
+                        finish_reason = Some(reason);
+                    }
+                }
+
+                // Emit chunk to frontend, coalescing bursts when batching
+                pending.push_str(&chunk);
+                match batch_interval {
+                    Some(interval) if pending.len() < batch_max_bytes => {
+                        flush_at.get_or_insert_with(|| tokio::time::Instant::now() + interval);
+                    }
+                    _ => {
+                        flush_at = None;
+                        flush_stream_batch(&window, &stream_id, &mut pending);
+                    }
+                }
+            }
+            Err(e) => {
+                flush_stream_batch(&window, &stream_id, &mut pending);
+                let message = redact(&e.to_string());
+                eprintln!("Stream error: {}", message);
+                let _ = window.emit(&format!("stream-error-{}", stream_id), &message);
+                break;
+            }
+        }
+    }
+
+    flush_stream_batch(&window, &stream_id, &mut pending);
+
+    // Matches split across chunks slip past the per-chunk pass
+    if let Some(filter) = &filter {
+        // Redacted chunks no longer contain their matches
+        let matches = filter.apply(&mut content);
+        let missed = if filter.redact { matches } else { matches.saturating_sub(flagged) };
+        if missed > 0 {
+            let _ = window.emit(&format!("stream-flagged-{}", stream_id), StreamFlagged {
+                matches: missed,
+                redacted: filter.redact,
+                final_message: true,
+            });
+        }
+    }
+
+    // Clean up
+    {
+        let mut map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+        map.remove(&stream_id);
+    }
+
+    if validate_json.unwrap_or(false) && !token.is_cancelled() {
+        let validation = if truncated {
+            JsonValidation {
+                valid: false,
+                error: Some(format!("Content was truncated at {} bytes", max_content_bytes)),
+                line: None,
+                column: None,
+            }
+        } else {
+            validate_json_content(&content)
+        };
+        let _ = window.emit(&format!("stream-json-valid-{}", stream_id), validation);
+    }
+
+    // Tool calls arrive fragmented, so they're only reported once complete
+    let tool_calls = tool_calls.finish();
+    if !tool_calls.is_empty() {
+        let _ = window.emit(&format!("stream-tool-calls-{}", stream_id), &tool_calls);
+    }
+
+    // Only partial answers are repaired: a complete one is left as the
+    // model wrote it
+    let cancelled = token.is_cancelled();
+    let content = if repair_partial_markdown.unwrap_or(false) && (cancelled || truncated || timed_out) {
+        repair_markdown_text(&content, true)
+    } else {
+        content
+    };
+
+    let _ = window.emit(&format!("stream-done-{}", stream_id), StreamDone {
+        cancelled,
+        total_bytes,
+        role,
+        finish_reason,
+        content,
+        truncated,
+        timed_out,
+    });
+
+    Ok(stream_id)
+}
+
+const STREAM_FILE_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamFileProgress {
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamFileResult {
+    pub path: String,
+    pub bytes_written: u64,
+    /// The file holds everything received before the cancel.
+    pub cancelled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+}
+
+/// Streams a reply's text straight into `path`, for outputs too large to
+/// pass through the webview. Only `stream-file-progress-{id}` events are
+/// emitted, a few times a second. Cancel with `cancel_stream`. `path` is
+/// only created (or truncated) once the upstream has accepted the request.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn stream_chat_to_file(
+    api_endpoint: String,
+    api_key: Option<String>,
+    request: ChatRequest,
+    path: String,
+    window: tauri::Window,
+    stream_id: Option<String>,
+    timeout_secs: Option<u64>,
+    idle_timeout_secs: Option<u64>,
+    image_limits: Option<ImageLimits>,
+    connect_timeout_secs: Option<u64>,
+    http: tauri::State<'_, HttpState>,
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<StreamFileResult, ProxyError> {
+    use futures::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    validate_images(&request.messages, &image_limits.unwrap_or_default())?;
+    let timeout_secs = resolve_timeout(timeout_secs)?;
+    let client = http.client_for(connect_timeout_secs)?;
+    let idle_timeout = match idle_timeout_secs {
+        Some(0) => return Err("Invalid timeout: idle_timeout_secs must be greater than 0".to_string().into()),
+        Some(secs) => std::time::Duration::from_secs(secs),
+        None => std::time::Duration::from_secs(DEFAULT_STREAM_IDLE_TIMEOUT_SECS),
+    };
+    let stream_id = stream_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let token = register_stream(&token_map, &stream_id)?.token;
+    let cleanup = || {
+        if let Ok(mut map) = token_map.lock() {
+            map.remove(&stream_id);
+        }
+    };
+
+    // Keep-alives only serve to reset the idle timer here
+    let stream = match proxy_chat_stream(client, &http.provider_defaults(), api_endpoint, api_key, request, timeout_secs, true).await {
+        Ok((_, stream)) => stream,
+        Err(e) => {
+            cleanup();
+            return Err(e);
+        }
+    };
+    tokio::pin!(stream);
+
+    let file = match tokio::fs::File::create(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            cleanup();
+            return Err(ProxyError::new(ErrorKind::Storage, format!("Failed to create {}: {}", path, e)));
+        }
+    };
+    let mut file = tokio::io::BufWriter::new(file);
+
+    let progress_event = format!("stream-file-progress-{}", stream_id);
+    let mut bytes_written = 0u64;
+    let mut finish_reason = None;
+    let mut last_progress = tokio::time::Instant::now();
+    let mut failure = None;
+
+    loop {
+        let chunk = tokio::select! {
+            next = stream.next() => match next {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => {
+                    failure = Some(ProxyError::new(ErrorKind::Network, format!("Stream error: {}", redact(&e.to_string()))));
+                    break;
+                }
+                None => break,
+            },
+            _ = token.cancelled() => break,
+            _ = tokio::time::sleep(idle_timeout) => {
+                failure = Some(ProxyError::new(
+                    ErrorKind::Network,
+                    format!("Stream timed out: no data received for {}s", idle_timeout.as_secs()),
+                ));
+                break;
+            }
+        };
+
+        for parsed in parse_stream_chunks(&chunk) {
+            if let Some(text) = chunk_content(&parsed) {
+                if let Err(e) = file.write_all(text.as_bytes()).await {
+                    failure = Some(ProxyError::new(ErrorKind::Storage, format!("Failed to write {}: {}", path, e)));
+                    break;
+                }
+                bytes_written += text.len() as u64;
+            }
+            if let Some(reason) = parsed.finish_reason() {
+                finish_reason = Some(reason);
+            }
+        }
+        if failure.is_some() {
+            break;
+        }
+
+        if last_progress.elapsed() >= STREAM_FILE_PROGRESS_INTERVAL {
+            last_progress = tokio::time::Instant::now();
+            let _ = window.emit(&progress_event, StreamFileProgress { bytes_written });
+        }
+    }
+
+    cleanup();
+    // Whatever arrived is kept on disk, even when the stream failed
+    if let Err(e) = file.shutdown().await {
+        failure.get_or_insert(ProxyError::new(ErrorKind::Storage, format!("Failed to write {}: {}", path, e)));
+    }
+    let _ = window.emit(&progress_event, StreamFileProgress { bytes_written });
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    Ok(StreamFileResult {
+        path,
+        bytes_written,
+        cancelled: token.is_cancelled(),
+        finish_reason,
+    })
+}
+
+const DEFAULT_SCAN_CACHE_TTL_MS: u64 = 3000;
+const SCAN_BACKOFF_BASE_MS: u64 = 1000;
+const SCAN_BACKOFF_MAX_MS: u64 = 30_000;
+
+type SharedScan = futures::future::Shared<futures::future::BoxFuture<'static, Result<Vec<OllamaModel>, ProxyError>>>;
+
+enum ScanEntry {
+    /// `failures` carries over from a previous failed entry.
+    InFlight {
+        scan: SharedScan,
+        failures: u32,
+    },
+    Ready {
+        at: std::time::Instant,
+        models: Vec<OllamaModel>,
+    },
+    /// Repeated failures back off exponentially, so an unreachable server
+    /// isn't retried on every focus event.
+    Failed {
+        at: std::time::Instant,
+        error: ProxyError,
+        failures: u32,
+    },
+}
+
+/// Recent and in-flight model scans, keyed by tags URL and key.
+#[derive(Default)]
+struct ScanCache(Mutex<HashMap<String, ScanEntry>>);
+
+const OFFLINE_MODELS_KEY: &str = "offline_models";
+
+/// Last successful scan of an endpoint, kept in the store for offline use.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OfflineModels {
+    cached_at: u64,
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelScan {
+    pub models: Vec<OllamaModel>,
+    /// The endpoint was unreachable and `models` comes from the last
+    /// successful scan, taken at `cached_at` (Unix seconds).
+    pub offline: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached_at: Option<u64>,
+}
+
+fn load_offline_models(app: &tauri::AppHandle, tags_url: &str) -> Option<OfflineModels> {
+    let store = app.store(STORE_FILE).ok()?;
+    serde_json::from_value(store.get(OFFLINE_MODELS_KEY)?.get(tags_url)?.clone()).ok()
+}
+
+/// Keyed by tags URL only, so API keys never end up on disk.
+fn save_offline_models(app: &tauri::AppHandle, tags_url: &str, models: &[OllamaModel]) -> Result<(), String> {
+    let store = app.store(STORE_FILE).map_err(|e| e.to_string())?;
+    let mut cache = store
+        .get(OFFLINE_MODELS_KEY)
+        .filter(serde_json::Value::is_object)
+        .unwrap_or_else(|| serde_json::json!({}));
+    let entry = OfflineModels {
+        cached_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        models: models.to_vec(),
+    };
+    cache[tags_url] = serde_json::to_value(entry).map_err(|e| e.to_string())?;
+    store.set(OFFLINE_MODELS_KEY, cache);
+    store.save().map_err(|e| e.to_string())
+}
+
+fn scan_backoff(failures: u32) -> std::time::Duration {
+    let delay = SCAN_BACKOFF_BASE_MS.saturating_mul(1 << failures.saturating_sub(1).min(16));
+    std::time::Duration::from_millis(delay.min(SCAN_BACKOFF_MAX_MS))
+}
+
+/// Scans are cached for `cache_ttl_ms` and concurrent scans of the same
+/// endpoint share one request. `force` skips both the cache and any backoff.
+/// When the endpoint can't be reached the last successful scan is returned
+/// with `offline` set; auth and other upstream errors still fail.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn scan_ollama_models(
+    app: tauri::AppHandle,
+    api_endpoint: String,
+    api_key: Option<String>,
+    user_agent: Option<String>,
+    force: Option<bool>,
+    cache_ttl_ms: Option<u64>,
+    http: tauri::State<'_, HttpState>,
+    cache: tauri::State<'_, ScanCache>,
+) -> Result<ModelScan, ProxyError> {
+    let tags_url = ollama_api_url(&api_endpoint, "/api/tags")?;
+    let save = |models: &[OllamaModel]| {
+        if let Err(e) = save_offline_models(&app, &tags_url, models) {
+            eprintln!("Failed to save offline model list: {}", e);
+        }
+    };
+    let scan = cached_scan(&tags_url, api_endpoint, api_key, user_agent, force, cache_ttl_ms, http.client.clone(), &cache, save);
+    match scan.await {
+        Ok(models) => Ok(ModelScan { models, offline: false, cached_at: None }),
+        Err(e) if e.kind == ErrorKind::Network => match load_offline_models(&app, &tags_url) {
+            Some(saved) => Ok(ModelScan { models: saved.models, offline: true, cached_at: Some(saved.cached_at) }),
+            None => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// `on_fresh` runs once per completed upstream scan, not for cache hits or
+/// callers that joined an in-flight scan.
+#[allow(clippy::too_many_arguments)]
+async fn cached_scan(
+    tags_url: &str,
+    api_endpoint: String,
+    api_key: Option<String>,
+    user_agent: Option<String>,
+    force: Option<bool>,
+    cache_ttl_ms: Option<u64>,
+    client: reqwest::Client,
+    cache: &ScanCache,
+    on_fresh: impl FnOnce(&[OllamaModel]),
+) -> Result<Vec<OllamaModel>, ProxyError> {
+    use futures::FutureExt;
+
+    let force = force.unwrap_or(false);
+    let ttl = std::time::Duration::from_millis(cache_ttl_ms.unwrap_or(DEFAULT_SCAN_CACHE_TTL_MS));
+    let key = format!("{}\n{}", tags_url, api_key.as_deref().unwrap_or_default());
+
+    let scan = {
+        let mut map = cache.0.lock().map_err(|e| format!("Lock error: {}", e))?;
+        match map.get(&key) {
+            Some(ScanEntry::Ready { at, models }) if !force && at.elapsed() < ttl => return Ok(models.clone()),
+            Some(ScanEntry::Failed { at, error, failures }) if !force && at.elapsed() < scan_backoff(*failures) => {
+                return Err(error.clone());
+            }
+            Some(ScanEntry::InFlight { scan, .. }) if !force => scan.clone(),
+            entry => {
+                let failures = match entry {
+                    Some(ScanEntry::Failed { failures, .. } | ScanEntry::InFlight { failures, .. }) => *failures,
+                    _ => 0,
+                };
+                let scan = proxy_scan_ollama_models(client, api_endpoint, api_key, user_agent)
+                    .boxed()
+                    .shared();
+                map.insert(key.clone(), ScanEntry::InFlight { scan: scan.clone(), failures });
+                scan
+            }
+        }
+    };
+
+    let result = scan.clone().await;
+
+    // Every waiter gets here; only the first to see its own scan records it
+    let mut map = cache.0.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let failures = match map.get(&key) {
+        Some(ScanEntry::InFlight { scan: current, failures }) if current.ptr_eq(&scan) => *failures,
+        _ => return result,
+    };
+    let at = std::time::Instant::now();
+    let entry = match &result {
+        Ok(models) => ScanEntry::Ready { at, models: models.clone() },
+        Err(error) => ScanEntry::Failed { at, error: error.clone(), failures: failures + 1 },
+    };
+    map.insert(key, entry);
+    drop(map);
+
+    if let Ok(models) = &result {
+        on_fresh(models);
+    }
+
+    result
+}
+
+#[tauri::command]
+async fn generate_embeddings(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    input: Vec<String>,
+    http: tauri::State<'_, HttpState>,
+) -> Result<Vec<Vec<f32>>, ProxyError> {
+    proxy_embeddings(http.client.clone(), api_endpoint, api_key, model, input).await
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn benchmark_model(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    provider: Option<String>,
+    max_tokens: Option<u64>,
+    timeout_secs: Option<u64>,
+    stream_id: Option<String>,
+    http: tauri::State<'_, HttpState>,
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<BenchmarkResult, ProxyError> {
+    let timeout_secs = resolve_timeout(timeout_secs)?;
+    let max_tokens = match max_tokens {
+        Some(0) => return Err("Invalid max_tokens: must be greater than 0".to_string().into()),
+        Some(n) => n,
+        None => DEFAULT_BENCHMARK_MAX_TOKENS,
+    };
+    let stream_id = stream_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    // Registered like a chat stream, so cancel_stream stops it
+    let token = register_stream(&token_map, &stream_id)?.token;
+
+    let result = proxy_benchmark_model(
+        http.client.clone(),
+        &http.provider_defaults(),
+        api_endpoint,
+        api_key,
+        model,
+        provider,
+        max_tokens,
+        timeout_secs,
+        &token,
+    )
+    .await;
+
+    // Clean up
+    {
+        let mut map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+        map.remove(&stream_id);
+    }
+
+    result
+}
+
+#[tauri::command]
+async fn measure_endpoint_latency(
+    api_endpoint: String,
+    api_key: Option<String>,
+    samples: Option<u32>,
+    http: tauri::State<'_, HttpState>,
+) -> Result<LatencyStats, ProxyError> {
+    let samples = samples.unwrap_or(DEFAULT_LATENCY_SAMPLES).clamp(1, MAX_LATENCY_SAMPLES);
+    proxy_measure_endpoint_latency(http.client.clone(), api_endpoint, api_key, samples).await
+}
+
+#[tauri::command]
+async fn validate_chat_config(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    provider: Option<String>,
+    http: tauri::State<'_, HttpState>,
+) -> Result<ChatConfigCheck, String> {
+    let defaults = http.provider_defaults();
+    let probe = proxy_validate_chat_config(http.client.clone(), &defaults, api_endpoint, api_key, model, provider);
+
+    Ok(tokio::time::timeout(std::time::Duration::from_secs(VALIDATE_TIMEOUT_SECS), probe)
+        .await
+        .unwrap_or_else(|_| {
+            ChatConfigCheck::from_error(ProxyError::new(
+                ErrorKind::Network,
+                format!("Endpoint did not respond within {}s", VALIDATE_TIMEOUT_SECS),
+            ))
+        }))
+}
+
+/// Backs the settings screen's "test key" button. The key is never logged
+/// or included in the result.
+#[tauri::command]
+async fn validate_api_key(
+    provider: Option<String>,
+    api_endpoint: String,
+    api_key: String,
+    http: tauri::State<'_, HttpState>,
+) -> Result<ApiKeyCheck, String> {
+    if api_key.trim().is_empty() {
+        return Err("Invalid API key: must not be empty".to_string());
+    }
+    let api_endpoint = http.provider_defaults().endpoint_for(provider.as_deref(), api_endpoint);
+
+    proxy_validate_api_key(http.client.clone(), api_endpoint, api_key.trim().to_string(), provider).await
+}
+
+#[tauri::command]
+async fn show_ollama_model(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    http: tauri::State<'_, HttpState>,
+) -> Result<OllamaModelInfo, ProxyError> {
+    proxy_show_ollama_model(http.client.clone(), api_endpoint, api_key, model).await
+}
+
+#[tauri::command]
+async fn delete_ollama_model(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    http: tauri::State<'_, HttpState>,
+) -> Result<(), ProxyError> {
+    proxy_delete_ollama_model(http.client.clone(), api_endpoint, api_key, model).await
+}
+
+#[tauri::command]
+async fn set_ollama_keep_alive(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    keep_alive: String,
+    http: tauri::State<'_, HttpState>,
+) -> Result<(), ProxyError> {
+    proxy_set_ollama_keep_alive(http.client.clone(), api_endpoint, api_key, model, keep_alive).await
+}
+
+#[tauri::command]
+async fn stop_ollama_model(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    http: tauri::State<'_, HttpState>,
+) -> Result<OllamaStopResult, ProxyError> {
+    proxy_stop_ollama_model(http.client.clone(), api_endpoint, api_key, model).await
+}
+
+/// Loads `model` so the first message doesn't pay for it. `keep_alive`
+/// controls how long it then stays loaded. Cancel with `cancel_stream`.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn warmup_model(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    keep_alive: Option<String>,
+    timeout_secs: Option<u64>,
+    stream_id: Option<String>,
+    http: tauri::State<'_, HttpState>,
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<WarmupResult, ProxyError> {
+    let timeout_secs = resolve_timeout(timeout_secs)?;
+    let stream_id = stream_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let token = register_stream(&token_map, &stream_id)?.token;
+
+    let result = proxy_warmup_ollama_model(
+        http.client.clone(),
+        api_endpoint,
+        api_key,
+        model,
+        keep_alive,
+        timeout_secs,
+        &token,
+    )
+    .await;
+
+    // Clean up
+    {
+        let mut map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+        map.remove(&stream_id);
+    }
+
+    result
+}
+
+#[tauri::command]
+async fn pull_ollama_model(
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    window: tauri::Window,
+    pull_id: Option<String>,
+    http: tauri::State<'_, HttpState>,
+) -> Result<String, ProxyError> {
+    let pull_id = pull_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let stream = proxy_pull_ollama_model(http.client.clone(), api_endpoint, api_key, model).await?;
+
+    use futures::StreamExt;
+
+    tokio::pin!(stream);
+
+    while let Some(progress_result) = stream.next().await {
+        match progress_result {
+            Ok(progress) => {
+                if let Some(error) = &progress.error {
+                    let _ = window.emit(&format!("pull-error-{}", pull_id), error);
+                    return Err(ProxyError::new(ErrorKind::Upstream, format!("Pull failed: {}", error)));
+                }
+                let _ = window.emit(&format!("pull-progress-{}", pull_id), &progress);
+            }
+            Err(e) => {
+                let _ = window.emit(&format!("pull-error-{}", pull_id), &e.to_string());
+                return Err(ProxyError::new(ErrorKind::Network, format!("Pull interrupted: {}", e)));
+            }
+        }
+    }
+
+    Ok(pull_id)
+}
+
+/// Stops reading a `send_chat_stream` upstream without cancelling it.
+/// Returns false for unknown ids.
+#[tauri::command]
+async fn pause_stream(
+    stream_id: String,
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<bool, String> {
+    set_stream_paused(&token_map, &stream_id, true)
+}
+
+#[tauri::command]
+async fn resume_stream(
+    stream_id: String,
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<bool, String> {
+    set_stream_paused(&token_map, &stream_id, false)
+}
+
+fn set_stream_paused(token_map: &TokenMap, stream_id: &str, paused: bool) -> Result<bool, String> {
+    let map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+
+    match map.get(stream_id) {
+        Some(ctx) => {
+            ctx.paused.send_replace(paused);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[tauri::command]
+async fn cancel_stream(
+    stream_id: String,
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<bool, String> {
+    let map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+
+    // The entry is removed by the command that owns the stream once it has
+    // wound down, so it can't clobber a newer stream reusing the same id
+    if let Some(ctx) = map.get(&stream_id) {
+        ctx.token.cancel();
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Cancels every stream. As with `cancel_stream`, entries stay in the map
+/// until their owners remove them; already-cancelled streams aren't counted.
+fn cancel_all(token_map: &TokenMap) -> Result<usize, String> {
+    let map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+
+    let mut count = 0;
+    for ctx in map.values().filter(|ctx| !ctx.token.is_cancelled()) {
+        ctx.token.cancel();
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[tauri::command]
+async fn cancel_all_streams(
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<usize, String> {
+    cancel_all(&token_map)
+}
+
+/// Cancels every stream whose id starts with `prefix`, under a single lock.
+/// Streams that were already cancelled aren't counted again.
+#[tauri::command]
+async fn cancel_streams_by_prefix(
+    prefix: String,
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<usize, String> {
+    if prefix.is_empty() {
+        return Err("Prefix must not be empty; use cancel_all_streams instead".to_string());
+    }
+    let map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+
+    let mut count = 0;
+    for (_, ctx) in map.iter().filter(|(id, _)| id.starts_with(&prefix)) {
+        if !ctx.token.is_cancelled() {
+            ctx.token.cancel();
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+#[tauri::command]
+async fn list_active_streams(
+    token_map: tauri::State<'_, TokenMap>,
+) -> Result<Vec<String>, String> {
+    let map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+    Ok(map.keys().cloned().collect())
+}
+
+#[tauri::command]
+async fn open_devtools(app: tauri::AppHandle) -> Result<(), String> {
+    if let Some(webview_window) = app.get_webview_window("main") {
+        webview_window.open_devtools();
+        Ok(())
+    } else {
+        Err("Failed to get webview window".to_string())
+    }
+}
+
+async fn stop_proxy_server(state: &ProxyState) -> Result<(), String> {
+    let _apply = state.apply_lock.lock().await;
+    stop_running_proxy(state).await
+}
+
+/// `stop_proxy_server` for callers already holding `apply_lock`.
+async fn stop_running_proxy(state: &ProxyState) -> Result<(), String> {
+    let existing_handle = {
+        let mut handle_lock = state.server_handle.lock().map_err(|e| e.to_string())?;
+        let mut addr_lock = state.bound_addr.lock().map_err(|e| e.to_string())?;
+        *addr_lock = None;
+        *state.running_config.lock().map_err(|e| e.to_string())? = None;
+        handle_lock.take()
+    };
+
+    if let Some(h) = existing_handle {
+        h.stop(true).await;
+        println!("HTTP proxy server stopped");
+    }
+
+    Ok(())
+}
+
+/// Rejects configs that `apply_proxy_server` would refuse to start.
+fn validate_proxy_config(config: &ProxyServerConfig) -> Result<(), String> {
+    if !config.enabled {
+        return Ok(());
+    }
+    if !is_loopback_host(&config.host) && config.auth_token.as_deref().is_none_or(str::is_empty) {
+        return Err(format!(
+            "Refusing to bind HTTP proxy to {} without an auth token: the proxy would be reachable by any device on the network and could be used to spend your API keys",
+            config.host
+        ));
+    }
+    if config.tls && config.tls_cert_path.is_some() != config.tls_key_path.is_some() {
+        return Err("TLS requires both tls_cert_path and tls_key_path".to_string());
+    }
+    Ok(())
+}
+
+async fn apply_proxy_server(
+    app: &tauri::AppHandle,
+    state: &ProxyState,
+    client: reqwest::Client,
+    config: &ProxyServerConfig,
+) -> Result<(), String> {
+    let _apply = state.apply_lock.lock().await;
+
+    let unchanged = state.running_config.lock().map_err(|e| e.to_string())?.as_ref() == Some(config);
+    if config.enabled && unchanged {
+        return Ok(());
+    }
+
+    // A config that can't be started must not take down the running server
+    validate_proxy_config(config)?;
+    let tls_config = if config.enabled && config.tls {
+        let (cert_path, key_path) = proxy_tls_paths(app, config)?;
+        Some(load_tls_config(&cert_path, &key_path)?)
+    } else {
+        None
+    };
+
+    // 1. Stop existing server if any
+    stop_running_proxy(state).await?;
+
+    // 2. Start new server if enabled
+    if config.enabled {
+        let host = config.host.clone();
+        let target_port = config.port;
+
+        state
+            .limiter
+            .limit
+            .store(config.max_concurrent_requests.unwrap_or(0), Ordering::Relaxed);
+
+        let logger = state.logger.clone();
+        let limiter = state.limiter.clone();
+        let upstream = state.upstream.clone();
+        let streams = state.streams.clone();
+        let client = web::Data::new(client);
+        let defaults = web::Data::from(app.state::<HttpState>().provider_defaults.clone());
+        let auth = web::Data::new(ProxyAuth(config.auth_token.clone().filter(|t| !t.is_empty())));
+        let json_config = proxy_json_config(config.max_body_bytes);
+        let request_timeout = config.request_timeout_secs.unwrap_or(DEFAULT_PROXY_REQUEST_TIMEOUT_SECS);
+        let allowed_origins = config.allowed_origins.clone();
+        let scheme = if tls_config.is_some() { "https" } else { "http" };
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(json_config.clone())
+                .app_data(web::Data::from(logger.clone()))
+                .app_data(client.clone())
+                .app_data(defaults.clone())
+                .app_data(auth.clone())
+                .app_data(web::Data::from(limiter.clone()))
+                .app_data(web::Data::from(upstream.clone()))
+                .app_data(web::Data::from(streams.clone()))
+                .wrap(actix_web::middleware::from_fn(limit_concurrency))
+                .wrap(actix_web::middleware::from_fn(require_proxy_token))
+                .wrap(build_cors(allowed_origins.as_deref()))
+                .wrap(actix_web::middleware::from_fn(propagate_request_id))
+                .route("/health", web::get().to(handle_health))
+                .route("/chat", web::post().to(handle_chat))
+                .route("/chat/stream", web::post().to(handle_chat_stream))
+                .route("/chat/cancel", web::post().to(handle_chat_cancel))
+                .route("/models", web::post().to(handle_models))
+                .route("/models/pull", web::post().to(handle_models_pull))
+                .route("/v1/chat/completions", web::post().to(handle_openai_chat_completions))
+        })
+        .client_request_timeout(std::time::Duration::from_secs(request_timeout))
+        // Open streams only get this long to finish, so they can't hold up
+        // a restart or app exit
+        .shutdown_timeout(PROXY_SHUTDOWN_TIMEOUT_SECS);
+        let server = match tls_config {
+            Some(tls_config) => server.bind_rustls_0_23((host.as_str(), target_port), tls_config),
+            None => server.bind((host.as_str(), target_port)),
+        }
+        .map_err(|e| format!("Failed to bind HTTP server to {}:{}: {}", host, target_port, e))?
+        .run();
+
+        let handle = server.handle();
+        let display_host = host.clone();
+        let generation = state.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            println!("HTTP proxy server running on {}://{}:{}", scheme, display_host, target_port);
+            let result = server.await;
+
+            // stop_proxy_server clears the handle before stopping, so a handle
+            // that is still registered means the server died on its own
+            let state = app.state::<ProxyState>();
+            let unexpected = state.generation.load(Ordering::SeqCst) == generation
+                && state.server_handle.lock().is_ok_and(|mut h| h.take().is_some());
+            if !unexpected {
+                return;
+            }
+            if let Ok(mut addr) = state.bound_addr.lock() {
+                *addr = None;
+            }
+            if let Ok(mut running) = state.running_config.lock() {
+                *running = None;
+            }
+
+            let message = match result {
+                Ok(()) => "HTTP proxy server stopped unexpectedly".to_string(),
+                Err(e) => format!("HTTP proxy server error: {}", e),
+            };
+            eprintln!("{}", message);
+            let _ = app.emit("proxy-server-error", &message);
+            let _ = app.emit("proxy-server-stopped", &message);
+        });
+
+        let mut handle_lock = state.server_handle.lock().map_err(|e| e.to_string())?;
+        *handle_lock = Some(handle);
+        let mut addr_lock = state.bound_addr.lock().map_err(|e| e.to_string())?;
+        *addr_lock = Some((host, target_port));
+        *state.running_config.lock().map_err(|e| e.to_string())? = Some(config.clone());
+    }
+
+    Ok(())
+}
+
+/// The store plugin treats an unparsable file as empty and overwrites it on
+/// the next save. Moving a corrupt file aside first keeps its contents
+/// recoverable; the app then starts from defaults. Call before the store
+/// is first opened.
+fn recover_corrupt_store(app: &tauri::AppHandle, file: &str) {
+    if let Ok(path) = tauri_plugin_store::resolve_store_path(app, file) {
+        back_up_corrupt_store(&path);
+    }
+}
+
+/// Renames `path` to `<name>.corrupt-<unix secs>` if it isn't a JSON
+/// object, returning where it went.
+fn back_up_corrupt_store(path: &std::path::Path) -> Option<std::path::PathBuf> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}; using defaults", path.display(), e);
+            return None;
+        }
+    };
+    let Err(e) = serde_json::from_slice::<HashMap<String, serde_json::Value>>(&bytes) else {
+        return None;
+    };
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut name = path.file_name()?.to_os_string();
+    name.push(format!(".corrupt-{}", timestamp));
+    let backup = path.with_file_name(name);
+    match std::fs::rename(path, &backup) {
+        Ok(()) => {
+            eprintln!("{} is corrupt ({}); moved it to {} and starting fresh", path.display(), e, backup.display());
+            Some(backup)
+        }
+        Err(rename_error) => {
+            eprintln!("{} is corrupt ({}) and could not be backed up: {}", path.display(), e, rename_error);
+            None
+        }
+    }
+}
+
+fn load_proxy_config(app: &tauri::AppHandle) -> Option<ProxyServerConfig> {
+    let store = app.store(STORE_FILE).ok()?;
+    match serde_json::from_value(store.get(PROXY_CONFIG_KEY)?) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            eprintln!("Ignoring invalid saved proxy server config: {}", e);
+            None
+        }
+    }
+}
+
+fn save_proxy_config(app: &tauri::AppHandle, config: &ProxyServerConfig) -> Result<(), String> {
+    let store = app.store(STORE_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    store.set(PROXY_CONFIG_KEY, value);
+    store.save().map_err(|e| e.to_string())
+}
+
+/// Starts, restarts or stops the proxy. Enabling it with the settings it is
+/// already running with does nothing, so in-flight requests survive.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn manage_proxy_server(
+    app: tauri::AppHandle,
+    enable: bool,
+    port: Option<u16>,
+    host: Option<String>,
+    auth_token: Option<String>,
+    max_concurrent_requests: Option<usize>,
+    max_body_bytes: Option<usize>,
+    request_timeout_secs: Option<u64>,
+    allowed_origins: Option<Vec<String>>,
+    tls: Option<bool>,
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    state: tauri::State<'_, ProxyState>,
+    http: tauri::State<'_, HttpState>,
+) -> Result<(), String> {
+    let allowed_origins = allowed_origins
+        .map(|origins| {
+            origins
+                .iter()
+                .filter(|o| !o.trim().is_empty())
+                .map(|o| normalize_origin(o))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?;
+    if max_body_bytes == Some(0) {
+        return Err("Invalid limit: max_body_bytes must be greater than 0".to_string());
+    }
+    if request_timeout_secs == Some(0) {
+        return Err("Invalid timeout: request_timeout_secs must be greater than 0".to_string());
+    }
+
+    let config = ProxyServerConfig {
+        enabled: enable,
+        port: port.unwrap_or(DEFAULT_PROXY_PORT),
+        host: host.unwrap_or_else(default_proxy_host),
+        auth_token,
+        max_concurrent_requests,
+        max_body_bytes,
+        request_timeout_secs,
+        allowed_origins,
+        tls: tls.unwrap_or(false),
+        tls_cert_path: tls_cert_path.filter(|p| !p.is_empty()),
+        tls_key_path: tls_key_path.filter(|p| !p.is_empty()),
+    };
+    validate_proxy_config(&config)?;
+    if let Err(e) = save_proxy_config(&app, &config) {
+        eprintln!("Failed to persist proxy server config: {}", e);
+    }
+
+    apply_proxy_server(&app, &state, http.client.clone(), &config).await
+}
+
+/// Sets the upstream used by `/v1/chat/completions` and by proxy requests
+/// that omit `api_endpoint`. An empty endpoint clears it.
+#[tauri::command]
+async fn set_proxy_upstream(
+    endpoint: String,
+    api_key: Option<String>,
+    state: tauri::State<'_, ProxyState>,
+) -> Result<(), String> {
+    let upstream = if endpoint.trim().is_empty() {
+        None
+    } else {
+        normalize_endpoint(&endpoint)?;
+        Some(ProxyUpstream {
+            endpoint: endpoint.trim().to_string(),
+            api_key: api_key.filter(|k| !k.is_empty()),
+        })
+    };
+
+    *state.upstream.lock().map_err(|e| e.to_string())? = upstream;
+
+    Ok(())
+}
+
+/// Returns the configured upstream with its key redacted.
+#[tauri::command]
+async fn get_proxy_upstream(state: tauri::State<'_, ProxyState>) -> Result<Option<ProxyUpstream>, String> {
+    let upstream = state.upstream.lock().map_err(|e| e.to_string())?.clone();
+
+    Ok(upstream.map(|u| ProxyUpstream {
+        endpoint: u.endpoint,
+        api_key: u.api_key.map(|_| "***".to_string()),
+    }))
+}
+
+/// Adjusts the proxy's concurrent request limit without restarting it.
+/// `None` or 0 removes the limit.
+#[tauri::command]
+async fn set_proxy_concurrency_limit(
+    limit: Option<usize>,
+    state: tauri::State<'_, ProxyState>,
+) -> Result<(), String> {
+    state.limiter.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the most recent proxy requests, oldest first.
+#[tauri::command]
+async fn get_proxy_log(state: tauri::State<'_, ProxyState>) -> Result<Vec<ProxyLogEntry>, String> {
+    let recent = state.logger.recent.lock().map_err(|e| e.to_string())?;
+    Ok(recent.iter().cloned().collect())
+}
+
+/// Formats Unix seconds as an RFC 3339 UTC timestamp.
+fn format_rfc3339(secs: u64) -> String {
+    // Civil-from-days, after Howard Hinnant's date algorithms
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Builds a HAR 1.2 document from the request log. The log holds metadata
+/// only, so entries carry sizes and timings but no headers beyond the
+/// request id, and no bodies or keys.
+fn proxy_log_har(entries: &[ProxyLogEntry], base_url: &str) -> serde_json::Value {
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| {
+            let method = if entry.route == "/health" { "GET" } else { "POST" };
+            let streamed = entry.route.ends_with("/stream") || entry.route.ends_with("/pull");
+            let mime_type = if streamed { "text/event-stream" } else { "application/json" };
+            let request_id = serde_json::json!([{ "name": "X-Request-Id", "value": entry.request_id }]);
+            let note = if streamed {
+                format!("Streamed response of {} bytes; stream bodies are not recorded", entry.bytes)
+            } else {
+                "Bodies are not recorded by the proxy log".to_string()
+            };
+
+            serde_json::json!({
+                "startedDateTime": format_rfc3339(entry.timestamp),
+                "time": entry.duration_ms,
+                "request": {
+                    "method": method,
+                    "url": format!("{}{}", base_url, entry.route),
+                    "httpVersion": "HTTP/1.1",
+                    "cookies": [],
+                    "headers": request_id,
+                    "queryString": [],
+                    "headersSize": -1,
+                    "bodySize": -1,
+                    "comment": entry.model.as_ref().map(|model| format!("model={}", model)).unwrap_or_default(),
+                },
+                "response": {
+                    "status": entry.status,
+                    "statusText": actix_web::http::StatusCode::from_u16(entry.status)
+                        .ok()
+                        .and_then(|status| status.canonical_reason())
+                        .unwrap_or_default(),
+                    "httpVersion": "HTTP/1.1",
+                    "cookies": [],
+                    "headers": request_id,
+                    "content": { "size": entry.bytes, "mimeType": mime_type, "comment": note },
+                    "redirectURL": "",
+                    "headersSize": -1,
+                    "bodySize": entry.bytes,
+                },
+                "cache": {},
+                "timings": { "send": 0, "wait": entry.duration_ms, "receive": 0 },
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "log": {
+            "version": "1.2",
+            "creator": { "name": "Miscuay", "version": env!("CARGO_PKG_VERSION") },
+            "entries": entries,
+        }
+    })
+}
+
+/// Writes the recent proxy requests to `path` as a HAR file for external
+/// tools. Returns the number of entries written.
+#[tauri::command]
+async fn export_proxy_har(path: String, state: tauri::State<'_, ProxyState>) -> Result<usize, String> {
+    let entries: Vec<ProxyLogEntry> = state.logger.recent.lock().map_err(|e| e.to_string())?.iter().cloned().collect();
+    let base_url = match state.running_config.lock().map_err(|e| e.to_string())?.as_ref() {
+        Some(config) => {
+            let scheme = if config.tls { "https" } else { "http" };
+            match config.host.contains(':') {
+                true => format!("{}://[{}]:{}", scheme, config.host, config.port),
+                false => format!("{}://{}:{}", scheme, config.host, config.port),
+            }
+        }
+        None => format!("http://{}:{}", default_proxy_host(), DEFAULT_PROXY_PORT),
+    };
+
+    let har = serde_json::to_string_pretty(&proxy_log_har(&entries, &base_url)).map_err(|e| e.to_string())?;
+    std::fs::write(&path, har).map_err(|e| format!("Failed to write HAR file {}: {}", path, e))?;
+
+    Ok(entries.len())
+}
+
+#[tauri::command]
+async fn clear_proxy_log(state: tauri::State<'_, ProxyState>) -> Result<(), String> {
+    state.logger.recent.lock().map_err(|e| e.to_string())?.clear();
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_proxy_logging(
+    app: tauri::AppHandle,
+    enabled: bool,
+    state: tauri::State<'_, ProxyState>,
+) -> Result<(), String> {
+    if enabled {
+        let log_dir = app
+            .path()
+            .app_data_dir()
+            .map_err(|e| format!("Failed to resolve app data dir: {}", e))?
+            .join("logs");
+        std::fs::create_dir_all(&log_dir)
+            .map_err(|e| format!("Failed to create log directory: {}", e))?;
+
+        let mut path_lock = state.logger.path.lock().map_err(|e| e.to_string())?;
+        *path_lock = Some(log_dir.join("proxy.log"));
+    }
+
+    state.logger.enabled.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_proxy_status(
+    state: tauri::State<'_, ProxyState>,
+) -> Result<ProxyStatus, String> {
+    let addr = state.bound_addr.lock().map_err(|e| e.to_string())?.clone();
+
+    Ok(ProxyStatus {
+        running: addr.is_some(),
+        host: addr.as_ref().map(|(host, _)| host.clone()),
+        port: addr.map(|(_, port)| port),
+    })
+}
+
+/// Derives a native Ollama API URL (e.g. `/api/tags`) from the configured
+/// endpoint, keeping its scheme, host and port.
+fn ollama_api_url(api_endpoint: &str, path: &str) -> Result<String, String> {
+    let url = normalize_endpoint(api_endpoint)?;
+
+    Ok(format!("{}{}", &url[..url::Position::BeforePath], path))
+}
+
+async fn proxy_scan_ollama_models(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    user_agent: Option<String>,
+) -> Result<Vec<OllamaModel>, ProxyError> {
+    let tags_url = ollama_api_url(&api_endpoint, "/api/tags")?;
+
+    let mut req_builder = client
+        .get(&tags_url)
+        .timeout(std::time::Duration::from_secs(SCAN_TIMEOUT_SECS))
+        .header("Content-Type", "application/json");
+
+    req_builder = add_auth_headers(req_builder, api_key);
+    req_builder = add_user_agent(req_builder, user_agent.as_deref());
+
+    let response = req_builder
+        .send()
+        .await
+        .map_err(ProxyError::network)?;
+
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+
+    let data: OllamaTagsResponse = response
+        .json()
+        .await
+        .map_err(ProxyError::parse)?;
+
+    Ok(data.models)
+}
+
+/// Sends an empty generate request so Ollama loads (or with "0", unloads) the
+/// model and keeps it resident for `keep_alive`.
+async fn proxy_set_ollama_keep_alive(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    keep_alive: String,
+) -> Result<(), ProxyError> {
+    let generate_url = ollama_api_url(&api_endpoint, "/api/generate")?;
+
+    let mut req_builder = client
+        .post(&generate_url)
+        .timeout(std::time::Duration::from_secs(DEFAULT_CHAT_TIMEOUT_SECS))
+        .header("Content-Type", "application/json");
+
+    req_builder = add_auth_headers(req_builder, api_key);
+
+    let response = req_builder
+        .json(&serde_json::json!({ "model": model, "keep_alive": keep_alive_value(&keep_alive) }))
+        .send()
+        .await
+        .map_err(ProxyError::network)?;
+
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaPsResponse {
+    #[serde(default)]
+    models: Vec<OllamaRunningModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaRunningModel {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    model: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OllamaStopResult {
+    pub stopped: bool,
+    /// Whether the model was in memory; `None` on Ollama versions without
+    /// `/api/ps`.
+    pub was_loaded: Option<bool>,
+}
+
+/// Lists loaded models via `/api/ps`, or `None` if the server predates it.
+async fn proxy_ollama_running_models(
+    client: &reqwest::Client,
+    api_endpoint: &str,
+    api_key: Option<String>,
+) -> Result<Option<Vec<String>>, ProxyError> {
+    let ps_url = ollama_api_url(api_endpoint, "/api/ps")?;
+
+    let response = add_auth_headers(client.get(&ps_url), api_key)
+        .timeout(std::time::Duration::from_secs(DEFAULT_CHAT_TIMEOUT_SECS))
+        .send()
+        .await
+        .map_err(ProxyError::network)?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+
+    let ps: OllamaPsResponse = response.json().await.map_err(ProxyError::parse)?;
+    Ok(Some(ps.models.into_iter().flat_map(|m| [m.name, m.model]).collect()))
+}
+
+/// Unloads a model from memory. This is what `ollama stop` does: a generate
+/// request with `keep_alive: 0` and no prompt.
+async fn proxy_stop_ollama_model(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+) -> Result<OllamaStopResult, ProxyError> {
+    let was_loaded = proxy_ollama_running_models(&client, &api_endpoint, api_key.clone())
+        .await?
+        .map(|running| running.iter().any(|name| model_matches(name, &model)));
+
+    if was_loaded == Some(false) {
+        return Ok(OllamaStopResult { stopped: false, was_loaded });
+    }
+
+    match proxy_set_ollama_keep_alive(client, api_endpoint, api_key, model.clone(), "0".to_string()).await {
+        Ok(()) => Ok(OllamaStopResult { stopped: true, was_loaded }),
+        Err(e) if e.status == Some(404) => Err(ProxyError {
+            kind: ErrorKind::Upstream,
+            status: Some(404),
+            message: format!("Model not found: {}", model),
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaLoadResponse {
+    /// Nanoseconds spent loading; near zero when the model was already in memory.
+    #[serde(default)]
+    load_duration: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WarmupResult {
+    pub loaded: bool,
+    pub cancelled: bool,
+    pub elapsed_ms: u64,
+    /// Load time as reported by Ollama, without network overhead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_duration_ms: Option<u64>,
+}
+
+/// Loads a model into memory ahead of the first message. A generate request
+/// without a prompt makes Ollama load the model and return without generating.
+async fn proxy_warmup_ollama_model(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    keep_alive: Option<String>,
+    timeout_secs: u64,
+    token: &CancellationToken,
+) -> Result<WarmupResult, ProxyError> {
+    let generate_url = ollama_api_url(&api_endpoint, "/api/generate")?;
+
+    let mut body = serde_json::json!({ "model": model, "stream": false });
+    if let Some(keep_alive) = keep_alive {
+        body["keep_alive"] = keep_alive_value(&keep_alive);
+    }
+
+    let req_builder = client
+        .post(&generate_url)
+        .timeout(std::time::Duration::from_secs(timeout_secs))
+        .header("Content-Type", "application/json");
+    let req_builder = add_auth_headers(req_builder, api_key).json(&body);
+
+    let started = std::time::Instant::now();
+    let response = tokio::select! {
+        _ = token.cancelled() => {
+            return Ok(WarmupResult {
+                loaded: false,
+                cancelled: true,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+                load_duration_ms: None,
+            });
+        }
+        response = req_builder.send() => response.map_err(ProxyError::network)?,
+    };
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(ProxyError {
+            kind: ErrorKind::Upstream,
+            status: Some(404),
+            message: format!("Model not found: {}", model),
+        });
+    }
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+
+    let loaded: OllamaLoadResponse = response.json().await.map_err(ProxyError::parse)?;
+    Ok(WarmupResult {
+        loaded: true,
+        cancelled: false,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        load_duration_ms: loaded.load_duration.map(|ns| ns / 1_000_000),
+    })
+}
+
+async fn proxy_show_ollama_model(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+) -> Result<OllamaModelInfo, ProxyError> {
+    let show_url = ollama_api_url(&api_endpoint, "/api/show")?;
+
+    let mut req_builder = client
+        .post(&show_url)
+        .timeout(std::time::Duration::from_secs(SCAN_TIMEOUT_SECS))
+        .header("Content-Type", "application/json");
+
+    req_builder = add_auth_headers(req_builder, api_key);
+
+    let response = req_builder
+        .json(&serde_json::json!({ "model": model, "name": model }))
+        .send()
+        .await
+        .map_err(ProxyError::network)?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(ProxyError {
+            kind: ErrorKind::Upstream,
+            status: Some(404),
+            message: format!("Model not found: {}", model),
+        });
+    }
+
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+
+    let data: OllamaShowResponse = response
+        .json()
+        .await
+        .map_err(ProxyError::parse)?;
+
+    let context_length = data.model_info.as_ref().and_then(|info| {
+        info.iter()
+            .find(|(key, _)| key.ends_with(".context_length"))
+            .and_then(|(_, value)| value.as_u64())
+    });
+    let details = data.details;
+
+    Ok(OllamaModelInfo {
+        name: model,
+        family: details.as_ref().and_then(|d| d.family.clone()),
+        parameter_size: details.as_ref().and_then(|d| d.parameter_size.clone()),
+        quantization_level: details.as_ref().and_then(|d| d.quantization_level.clone()),
+        context_length,
+        template: data.template,
+    })
+}
+
+async fn proxy_delete_ollama_model(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+) -> Result<(), ProxyError> {
+    let delete_url = ollama_api_url(&api_endpoint, "/api/delete")?;
+
+    let mut req_builder = client
+        .delete(&delete_url)
+        .timeout(std::time::Duration::from_secs(SCAN_TIMEOUT_SECS))
+        .header("Content-Type", "application/json");
+
+    req_builder = add_auth_headers(req_builder, api_key);
+
+    let response = req_builder
+        .json(&serde_json::json!({ "model": model, "name": model }))
+        .send()
+        .await
+        .map_err(ProxyError::network)?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(ProxyError {
+            kind: ErrorKind::Upstream,
+            status: Some(404),
+            message: format!("Model not found: {}", model),
+        });
+    }
+
+    if !response.status().is_success() {
+        return Err(ProxyError::from_response(response).await);
+    }
+
+    Ok(())
+}
+
+async fn proxy_pull_ollama_model(
+    client: reqwest::Client,
+    api_endpoint: String,
+    api_key: Option<String>,
+    model: String,
+) -> Result<impl futures::Stream<Item = Result<PullProgress, std::io::Error>>, ProxyError> {
+    let pull_url = ollama_api_url(&api_endpoint, "/api/pull")?;
+
+    let mut req_builder = client.post(&pull_url).header("Content-Type", "application/json");
+
+    req_builder = add_auth_headers(req_builder, api_key);
+    req_builder = req_builder.json(&serde_json::json!({ "model": model, "name": model, "stream": true }));
+
+    let response = match send_stream_request(req_builder, SCAN_TIMEOUT_SECS).await {
+        Err(e) if e.status == Some(404) || (e.status.is_some() && e.message.contains("does not exist")) => {
+            return Err(ProxyError {
+                kind: ErrorKind::Upstream,
+                status: e.status,
+                message: format!("Model not found: {}", model),
+            });
+        }
+        response => response?,
+    };
+
+    let s = stream! {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut reader = stream_body_reader(response);
+        let mut buf = Vec::new();
+
+        // Ollama reports progress as newline-delimited JSON, one object per line
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf);
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+
+                    let progress: PullProgress = match serde_json::from_str(&line) {
+                        Ok(progress) => progress,
+                        Err(e) => {
+                            yield Err(std::io::Error::other(format!("Invalid progress line: {}", e)));
+                            break;
+                        }
+                    };
+
+                    let finished = progress.error.is_some() || progress.status == "success";
+                    yield Ok(progress);
+                    if finished {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+    };
+
+    Ok(s)
+}
+
+const CONVERSATIONS_STORE_FILE: &str = "conversations.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredConversation {
+    messages: Vec<ChatMessage>,
+    updated_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub message_count: usize,
+    pub updated_at: u64,
+}
+
+/// Serializes conversation writes so a save can't interleave with another
+/// save's flush to disk.
+#[derive(Default)]
+struct ConversationStoreLock(Mutex<()>);
+
+fn conversation_store(
+    app: &tauri::AppHandle,
+) -> Result<Arc<tauri_plugin_store::Store<tauri::Wry>>, ProxyError> {
+    app.store(CONVERSATIONS_STORE_FILE)
+        .map_err(|e| ProxyError::new(ErrorKind::Storage, format!("Failed to open conversation store: {}", e)))
+}
+
+#[tauri::command]
+async fn save_conversation(
+    app: tauri::AppHandle,
+    id: String,
+    messages: Vec<ChatMessage>,
+    lock: tauri::State<'_, ConversationStoreLock>,
+) -> Result<(), ProxyError> {
+    if id.trim().is_empty() {
+        return Err("Conversation id is empty".to_string().into());
+    }
+
+    let updated_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let value = serde_json::to_value(StoredConversation { messages, updated_at })
+        .map_err(|e| ProxyError::new(ErrorKind::Storage, format!("Failed to serialize conversation: {}", e)))?;
+
+    let _guard = lock.0.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let store = conversation_store(&app)?;
+    store.set(id, value);
+    store
+        .save()
+        .map_err(|e| ProxyError::new(ErrorKind::Storage, format!("Failed to save conversation: {}", e)))
+}
+
+#[tauri::command]
+async fn load_conversation(app: tauri::AppHandle, id: String) -> Result<Option<Vec<ChatMessage>>, ProxyError> {
+    let store = conversation_store(&app)?;
+    let Some(value) = store.get(&id) else {
+        return Ok(None);
+    };
+
+    let conversation: StoredConversation = serde_json::from_value(value).map_err(|e| {
+        ProxyError::new(ErrorKind::Storage, format!("Conversation {} is corrupt: {}", id, e))
+    })?;
+
+    Ok(Some(conversation.messages))
+}
+
+/// Lists stored conversations, most recently updated first. Corrupt entries
+/// are skipped here and reported by `load_conversation`.
+#[tauri::command]
+async fn list_conversations(app: tauri::AppHandle) -> Result<Vec<ConversationSummary>, ProxyError> {
+    let store = conversation_store(&app)?;
+
+    let mut summaries: Vec<ConversationSummary> = store
+        .entries()
+        .into_iter()
+        .filter_map(|(id, value)| match serde_json::from_value::<StoredConversation>(value) {
+            Ok(conversation) => Some(ConversationSummary {
+                id,
+                message_count: conversation.messages.len(),
+                updated_at: conversation.updated_at,
+            }),
+            Err(e) => {
+                eprintln!("Skipping corrupt conversation {}: {}", id, e);
+                None
+            }
+        })
+        .collect();
+
+    summaries.sort_by_key(|s| std::cmp::Reverse(s.updated_at));
+
+    Ok(summaries)
+}
+
+fn image_markdown(image: &str) -> String {
+    if image.starts_with("data:") || image.starts_with("http://") || image.starts_with("https://") {
+        format!("![image]({})", image)
+    } else {
+        // Ollama-style raw base64
+        format!("![image](data:image/png;base64,{})", image)
+    }
+}
+
+/// Renders message content, which is either a plain string or an array of
+/// OpenAI-style parts. Anything else is dumped as a JSON block.
+fn content_markdown(content: &serde_json::Value) -> String {
+    match content {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::Array(parts) => parts
+            .iter()
+            .map(|part| match part {
+                serde_json::Value::String(text) => text.clone(),
+                _ => match part.get("type").and_then(|t| t.as_str()) {
+                    Some("text") => part.get("text").and_then(|t| t.as_str()).unwrap_or_default().to_string(),
+                    Some("image_url") => {
+                        let url = part
+                            .get("image_url")
+                            .and_then(|i| i.get("url").or(Some(i)))
+                            .and_then(|u| u.as_str())
+                            .unwrap_or_default();
+                        image_markdown(url)
+                    }
+                    _ => json_block(part),
+                },
+            })
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        other => json_block(other),
+    }
+}
+
+fn json_block(value: &serde_json::Value) -> String {
+    format!("```json\n{}\n```", serde_json::to_string_pretty(value).unwrap_or_default())
+}
+
+fn conversation_markdown(messages: &[ChatMessage]) -> String {
+    let mut out = String::new();
+
+    for message in messages {
+        let mut role = message.role.clone();
+        if let Some(first) = role.get_mut(..1) {
+            first.make_ascii_uppercase();
+        }
+
+        out.push_str(&format!("## {}\n\n", role));
+
+        let body = content_markdown(&message.content);
+        if !body.is_empty() {
+            out.push_str(body.trim_end());
+            out.push_str("\n\n");
+        }
+
+        for image in message.images.iter().flatten() {
+            out.push_str(&image_markdown(image));
+            out.push_str("\n\n");
+        }
+    }
+
+    out
+}
+
+/// Renders a conversation as Markdown. With `path` the result is also written
+/// there and the file is revealed in its folder.
+#[tauri::command]
+async fn export_conversation_markdown(
+    app: tauri::AppHandle,
+    messages: Vec<ChatMessage>,
+    path: Option<String>,
+) -> Result<String, String> {
+    use tauri_plugin_opener::OpenerExt;
+
+    let markdown = conversation_markdown(&messages);
+
+    if let Some(path) = path {
+        std::fs::write(&path, &markdown).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        if let Err(e) = app.opener().reveal_item_in_dir(&path) {
+            eprintln!("Failed to reveal exported file: {}", e);
+        }
+    }
+
+    Ok(markdown)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageSaveFailure {
+    pub index: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedImages {
+    pub written: Vec<String>,
+    pub failed: Vec<ImageSaveFailure>,
+}
+
+/// File extension for an image, from its magic bytes or else the data URL's
+/// mime type. Raw Ollama-style base64 carries no mime type at all.
+fn image_extension(image: &str, bytes: &[u8]) -> &'static str {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => return "png",
+        [0xFF, 0xD8, 0xFF, ..] => return "jpg",
+        [b'G', b'I', b'F', b'8', ..] => return "gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => return "webp",
+        _ => {}
+    }
+
+    let mime = image
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split([';', ',']).next())
+        .unwrap_or_default();
+    match mime {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        _ => "png",
+    }
+}
+
+/// Decodes each image and writes it into `dir`. Images that fail to decode
+/// or write are reported individually so the rest are still saved.
+#[tauri::command]
+async fn save_message_images(images: Vec<String>, dir: String) -> Result<SavedImages, String> {
+    let dir = PathBuf::from(dir);
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut saved = SavedImages { written: Vec::new(), failed: Vec::new() };
+
+    for (index, image) in images.iter().enumerate() {
+        let bytes = match decode_image_payload(image) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                saved.failed.push(ImageSaveFailure { index, error: e });
+                continue;
+            }
+        };
+
+        let ext = image_extension(image, &bytes);
+        let mut path = dir.join(format!("image-{}-{}.{}", stamp, index + 1, ext));
+        let mut copy = 1;
+        while path.exists() {
+            copy += 1;
+            path = dir.join(format!("image-{}-{}-{}.{}", stamp, index + 1, copy, ext));
+        }
+
+        match std::fs::write(&path, &bytes) {
+            Ok(()) => saved.written.push(path.to_string_lossy().into_owned()),
+            Err(e) => saved.failed.push(ImageSaveFailure {
+                index,
+                error: format!("Failed to write {}: {}", path.display(), e),
+            }),
+        }
+    }
+
+    Ok(saved)
+}
+
+/// Per-message framing tokens in OpenAI's chat format, plus the tokens that
+/// prime the assistant's reply.
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_PER_REPLY: usize = 3;
+/// A low-detail image in OpenAI's accounting; other providers are similar.
+const TOKENS_PER_IMAGE: usize = 85;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenCount {
+    pub total: usize,
+    pub per_message: Vec<usize>,
+    /// tiktoken encoding used, or "estimate" for models it doesn't know.
+    pub encoding: &'static str,
+}
+
+/// Picks the tiktoken encoding for a model, ignoring gateway prefixes such
+/// as OpenRouter's `openai/`.
+fn model_encoding(model: &str) -> Option<(&'static str, &'static tiktoken_rs::CoreBPE)> {
+    use tiktoken_rs::tokenizer::Tokenizer;
+
+    let name = model.rsplit('/').next().unwrap_or(model);
+    match tiktoken_rs::tokenizer::get_tokenizer(name)? {
+        Tokenizer::O200kBase => Some(("o200k_base", tiktoken_rs::o200k_base_singleton())),
+        Tokenizer::Cl100kBase => Some(("cl100k_base", tiktoken_rs::cl100k_base_singleton())),
+        Tokenizer::P50kBase => Some(("p50k_base", tiktoken_rs::p50k_base_singleton())),
+        Tokenizer::P50kEdit => Some(("p50k_edit", tiktoken_rs::p50k_edit_singleton())),
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => Some(("r50k_base", tiktoken_rs::r50k_base_singleton())),
+    }
+}
+
+/// Rough count for unknown tokenizers: about four ASCII characters per
+/// token, and one token per character for other scripts.
+fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.bytes().filter(u8::is_ascii).count();
+    let other = text.chars().filter(|c| !c.is_ascii()).count();
+    ascii.div_ceil(4) + other
+}
+
+/// Converts a conversation for another provider, returning the request body
+/// the adapter would send: system prompts moved to Anthropic's `system`
+/// field, Gemini's "model" role and `contents` layout, and so on. History
+/// recorded from Gemini ("model" turns) is accepted for every target.
+#[tauri::command]
+async fn prepare_messages_for_provider(
+    provider: String,
+    messages: Vec<ChatMessage>,
+    model: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let target = resolve_provider(Some(&provider), "")?;
+    let messages = messages
+        .into_iter()
+        .map(|message| match message.role.as_str() {
+            "model" => ChatMessage { role: "assistant".to_string(), ..message },
+            _ => message,
+        })
+        .collect();
+
+    let request = ChatRequest {
+        model: model.unwrap_or_default(),
+        messages,
+        stream: false,
+        options: None,
+        max_tokens: None,
+        temperature: None,
+        top_p: None,
+        keep_alive: None,
+        provider: Some(provider),
+        headers: None,
+        user_agent: None,
+        override_content_type: false,
+    };
+    validate_message_content(&request.messages)?;
+
+    Ok(build_chat_body(&request, target))
+}
+
+/// Estimates the prompt size of `messages` for `model` without any network
+/// access.
+#[tauri::command]
+async fn count_tokens(model: String, messages: Vec<ChatMessage>) -> Result<TokenCount, String> {
+    Ok(count_message_tokens(&model, &messages))
+}
+
+fn count_message_tokens(model: &str, messages: &[ChatMessage]) -> TokenCount {
+    let encoding = model_encoding(model);
+    let count = |text: &str| match encoding {
+        Some((_, bpe)) => bpe.encode_ordinary(text).len(),
+        None => estimate_tokens(text),
+    };
+
+    let per_message: Vec<usize> = messages
+        .iter()
+        .map(|message| {
+            let mut tokens = TOKENS_PER_MESSAGE + count(&message.role);
+            match &message.content {
+                serde_json::Value::String(text) => tokens += count(text),
+                serde_json::Value::Array(parts) => {
+                    for part in parts {
+                        match part.get("type").and_then(|t| t.as_str()) {
+                            Some("text") => tokens += count(part.get("text").and_then(|t| t.as_str()).unwrap_or_default()),
+                            Some("image_url") => tokens += TOKENS_PER_IMAGE,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+            tokens + message.images.as_ref().map_or(0, |images| images.len() * TOKENS_PER_IMAGE)
+        })
+        .collect();
+
+    TokenCount {
+        total: per_message.iter().sum::<usize>() + TOKENS_PER_REPLY,
+        per_message,
+        encoding: encoding.map_or("estimate", |(name, _)| name),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrimStrategy {
+    /// Drops the oldest messages, system prompts included.
+    DropOldest,
+    /// Drops the oldest messages but never a system prompt.
+    KeepSystem,
+    /// Like `KeepSystem`, with a note where messages were removed so the
+    /// model knows the history is incomplete.
+    SummarizePlaceholder,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrimmedMessages {
+    pub messages: Vec<ChatMessage>,
+    pub dropped: usize,
+    pub total_tokens: usize,
+    /// False when even the messages that are never dropped (the latest
+    /// message, and system prompts when kept) exceed the budget.
+    pub fits: bool,
+}
+
+/// Drops old messages until the estimated prompt fits in `budget` tokens.
+/// The most recent message is always kept.
+#[tauri::command]
+async fn trim_messages_to_budget(
+    model: String,
+    messages: Vec<ChatMessage>,
+    budget: usize,
+    strategy: TrimStrategy,
+) -> Result<TrimmedMessages, String> {
+    let counts = count_message_tokens(&model, &messages).per_message;
+    let protected = |index: usize| {
+        index + 1 == messages.len() || (strategy != TrimStrategy::DropOldest && messages[index].role == "system")
+    };
+
+    let placeholder = |dropped: usize| ChatMessage {
+        role: "system".to_string(),
+        content: serde_json::Value::String(format!(
+            "[{} earlier message{} omitted to fit the context window]",
+            dropped,
+            if dropped == 1 { "" } else { "s" }
+        )),
+        images: None,
+    };
+    let placeholder_tokens = match strategy {
+        TrimStrategy::SummarizePlaceholder => count_message_tokens(&model, &[placeholder(messages.len())]).per_message[0],
+        _ => 0,
+    };
+
+    let mut total = counts.iter().sum::<usize>() + TOKENS_PER_REPLY;
+    let mut keep = vec![true; messages.len()];
+    let mut dropped = 0;
+    for index in 0..messages.len() {
+        let over = match dropped {
+            0 => total > budget,
+            _ => total + placeholder_tokens > budget,
+        };
+        if !over {
             break;
         }
+        if !protected(index) {
+            keep[index] = false;
+            total -= counts[index];
+            dropped += 1;
+        }
+    }
+    if dropped > 0 {
+        total += placeholder_tokens;
+    }
+
+    let mut trimmed = Vec::with_capacity(messages.len() - dropped + 1);
+    let mut placed = false;
+    for (message, keep) in messages.into_iter().zip(keep) {
+        if keep {
+            trimmed.push(message);
+        } else if strategy == TrimStrategy::SummarizePlaceholder && !placed {
+            trimmed.push(placeholder(dropped));
+            placed = true;
+        }
+    }
+
+    Ok(TrimmedMessages {
+        messages: trimmed,
+        dropped,
+        total_tokens: total,
+        fits: total <= budget,
+    })
+}
+
+/// Expected shape of a model option, for pre-flight warnings only.
+#[derive(Debug, Clone, Copy)]
+enum OptionSpec {
+    Number { min: f64, max: f64 },
+    Integer { min: i64, max: i64 },
+    Bool,
+    Text,
+    /// A string or a list of strings, like `stop`.
+    Strings,
+    Any,
+}
+
+const UNBOUNDED: f64 = f64::MAX;
+
+const OLLAMA_OPTIONS: &[(&str, OptionSpec)] = &[
+    ("temperature", OptionSpec::Number { min: 0.0, max: 2.0 }),
+    ("top_p", OptionSpec::Number { min: 0.0, max: 1.0 }),
+    ("top_k", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("min_p", OptionSpec::Number { min: 0.0, max: 1.0 }),
+    ("typical_p", OptionSpec::Number { min: 0.0, max: 1.0 }),
+    ("tfs_z", OptionSpec::Number { min: 0.0, max: UNBOUNDED }),
+    ("num_ctx", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("num_predict", OptionSpec::Integer { min: -2, max: i64::MAX }),
+    ("num_keep", OptionSpec::Integer { min: -1, max: i64::MAX }),
+    ("num_batch", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("num_gpu", OptionSpec::Integer { min: -1, max: i64::MAX }),
+    ("main_gpu", OptionSpec::Integer { min: 0, max: i64::MAX }),
+    ("num_thread", OptionSpec::Integer { min: 0, max: i64::MAX }),
+    ("repeat_penalty", OptionSpec::Number { min: 0.0, max: UNBOUNDED }),
+    ("repeat_last_n", OptionSpec::Integer { min: -1, max: i64::MAX }),
+    ("presence_penalty", OptionSpec::Number { min: -2.0, max: 2.0 }),
+    ("frequency_penalty", OptionSpec::Number { min: -2.0, max: 2.0 }),
+    ("penalize_newline", OptionSpec::Bool),
+    ("mirostat", OptionSpec::Integer { min: 0, max: 2 }),
+    ("mirostat_eta", OptionSpec::Number { min: 0.0, max: UNBOUNDED }),
+    ("mirostat_tau", OptionSpec::Number { min: 0.0, max: UNBOUNDED }),
+    ("seed", OptionSpec::Integer { min: i64::MIN, max: i64::MAX }),
+    ("stop", OptionSpec::Strings),
+    ("numa", OptionSpec::Bool),
+    ("low_vram", OptionSpec::Bool),
+    ("use_mmap", OptionSpec::Bool),
+    ("use_mlock", OptionSpec::Bool),
+];
+
+const OPENAI_OPTIONS: &[(&str, OptionSpec)] = &[
+    ("temperature", OptionSpec::Number { min: 0.0, max: 2.0 }),
+    ("top_p", OptionSpec::Number { min: 0.0, max: 1.0 }),
+    ("max_tokens", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("max_completion_tokens", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("presence_penalty", OptionSpec::Number { min: -2.0, max: 2.0 }),
+    ("frequency_penalty", OptionSpec::Number { min: -2.0, max: 2.0 }),
+    ("seed", OptionSpec::Integer { min: i64::MIN, max: i64::MAX }),
+    ("stop", OptionSpec::Strings),
+    ("n", OptionSpec::Integer { min: 1, max: 128 }),
+    ("logprobs", OptionSpec::Bool),
+    ("top_logprobs", OptionSpec::Integer { min: 0, max: 20 }),
+    ("logit_bias", OptionSpec::Any),
+    ("response_format", OptionSpec::Any),
+    ("tools", OptionSpec::Any),
+    ("tool_choice", OptionSpec::Any),
+    ("parallel_tool_calls", OptionSpec::Bool),
+    ("reasoning_effort", OptionSpec::Text),
+    ("stream_options", OptionSpec::Any),
+    ("user", OptionSpec::Text),
+];
+
+const ANTHROPIC_OPTIONS: &[(&str, OptionSpec)] = &[
+    ("temperature", OptionSpec::Number { min: 0.0, max: 1.0 }),
+    ("top_p", OptionSpec::Number { min: 0.0, max: 1.0 }),
+    ("top_k", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("max_tokens", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("stop_sequences", OptionSpec::Strings),
+    ("metadata", OptionSpec::Any),
+    ("tools", OptionSpec::Any),
+    ("tool_choice", OptionSpec::Any),
+    ("thinking", OptionSpec::Any),
+];
+
+const GEMINI_OPTIONS: &[(&str, OptionSpec)] = &[
+    ("temperature", OptionSpec::Number { min: 0.0, max: 2.0 }),
+    ("topP", OptionSpec::Number { min: 0.0, max: 1.0 }),
+    ("topK", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("maxOutputTokens", OptionSpec::Integer { min: 1, max: i64::MAX }),
+    ("candidateCount", OptionSpec::Integer { min: 1, max: 8 }),
+    ("stopSequences", OptionSpec::Strings),
+    ("presencePenalty", OptionSpec::Number { min: -2.0, max: 2.0 }),
+    ("frequencyPenalty", OptionSpec::Number { min: -2.0, max: 2.0 }),
+    ("seed", OptionSpec::Integer { min: i64::MIN, max: i64::MAX }),
+    ("responseMimeType", OptionSpec::Text),
+    ("responseSchema", OptionSpec::Any),
+    ("responseLogprobs", OptionSpec::Bool),
+    ("logprobs", OptionSpec::Integer { min: 0, max: 20 }),
+    ("thinkingConfig", OptionSpec::Any),
+];
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionIssue {
+    Unknown,
+    WrongType,
+    OutOfRange,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OptionWarning {
+    /// Empty when the options value itself is the problem.
+    pub key: String,
+    pub issue: OptionIssue,
+    pub message: String,
+    /// The known key closest to a mistyped one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OptionsReport {
+    pub warnings: Vec<OptionWarning>,
+    /// The options as indented JSON, for display.
+    pub pretty: String,
+}
+
+/// Levenshtein distance, used to suggest the intended key for a typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn check_option(key: &str, value: &serde_json::Value, spec: OptionSpec) -> Option<(OptionIssue, String)> {
+    let wrong_type = |expected: &str| Some((OptionIssue::WrongType, format!("'{}' should be {}", key, expected)));
+    match spec {
+        OptionSpec::Number { min, max } => match value.as_f64() {
+            None => wrong_type("a number"),
+            Some(n) if n < min || n > max => {
+                let range = if max == UNBOUNDED { format!("at least {}", min) } else { format!("between {} and {}", min, max) };
+                Some((OptionIssue::OutOfRange, format!("'{}' is {}, expected {}", key, n, range)))
+            }
+            Some(_) => None,
+        },
+        OptionSpec::Integer { min, max } => match value.as_i64() {
+            None => wrong_type("an integer"),
+            Some(n) if n < min || n > max => {
+                let range = if max == i64::MAX { format!("at least {}", min) } else { format!("between {} and {}", min, max) };
+                Some((OptionIssue::OutOfRange, format!("'{}' is {}, expected {}", key, n, range)))
+            }
+            Some(_) => None,
+        },
+        OptionSpec::Bool if !value.is_boolean() => wrong_type("true or false"),
+        OptionSpec::Text if !value.is_string() => wrong_type("a string"),
+        OptionSpec::Strings => match value {
+            serde_json::Value::String(_) => None,
+            serde_json::Value::Array(items) if items.iter().all(serde_json::Value::is_string) => None,
+            _ => wrong_type("a string or a list of strings"),
+        },
+        _ => None,
+    }
+}
+
+/// Checks `options` against the keys each provider is known to accept and
+/// reports unknown, mistyped or out-of-range ones. Nothing is rejected;
+/// requests still send whatever was given.
+#[tauri::command]
+async fn validate_model_options(
+    provider: Option<String>,
+    api_endpoint: Option<String>,
+    options: serde_json::Value,
+) -> Result<OptionsReport, String> {
+    let provider = resolve_provider(provider.as_deref(), api_endpoint.as_deref().unwrap_or_default())?;
+    let schema = match provider {
+        Provider::Ollama => OLLAMA_OPTIONS,
+        Provider::OpenAi => OPENAI_OPTIONS,
+        Provider::Anthropic => ANTHROPIC_OPTIONS,
+        Provider::Gemini => GEMINI_OPTIONS,
+    };
+
+    let mut warnings = Vec::new();
+    match &options {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                match schema.iter().find(|(name, _)| name == key) {
+                    Some((_, spec)) => {
+                        if let Some((issue, message)) = check_option(key, value, *spec) {
+                            warnings.push(OptionWarning { key: key.clone(), issue, message, suggestion: None });
+                        }
+                    }
+                    None => {
+                        let suggestion = schema
+                            .iter()
+                            .map(|(name, _)| (edit_distance(&key.to_ascii_lowercase(), &name.to_ascii_lowercase()), *name))
+                            .filter(|(distance, _)| *distance <= 2)
+                            .min_by_key(|(distance, _)| *distance)
+                            .map(|(_, name)| name.to_string());
+                        let message = match &suggestion {
+                            Some(name) => format!("Unknown option '{}'; did you mean '{}'?", key, name),
+                            None => format!("Unknown option '{}' will probably be ignored", key),
+                        };
+                        warnings.push(OptionWarning { key: key.clone(), issue: OptionIssue::Unknown, message, suggestion });
+                    }
+                }
+            }
+        }
+        serde_json::Value::Null => {}
+        _ => warnings.push(OptionWarning {
+            key: String::new(),
+            issue: OptionIssue::WrongType,
+            message: "Options should be a JSON object and will be ignored".to_string(),
+            suggestion: None,
+        }),
+    }
+
+    Ok(OptionsReport {
+        warnings,
+        pretty: serde_json::to_string_pretty(&options).map_err(|e| e.to_string())?,
+    })
+}
+
+/// Opens a link from chat content in the default browser. Only http(s) is
+/// allowed, so injected links can't launch file:// or custom URL handlers.
+#[tauri::command]
+async fn open_external_url(app: tauri::AppHandle, url: String) -> Result<(), String> {
+    use tauri_plugin_opener::OpenerExt;
+
+    let parsed = url::Url::parse(url.trim()).map_err(|e| format!("Invalid URL '{}': {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!(
+            "Refusing to open '{}' links: only http and https URLs are allowed",
+            parsed.scheme()
+        ));
+    }
+    if parsed.host().is_none() {
+        return Err(format!("Invalid URL '{}': missing host", url));
+    }
+
+    app.opener()
+        .open_url(parsed.as_str(), None::<&str>)
+        .map_err(|e| format!("Failed to open {}: {}", parsed, e))
+}
+
+#[tauri::command]
+async fn copy_to_clipboard(app: tauri::AppHandle, text: String) -> Result<(), String> {
+    use tauri_plugin_clipboard_manager::ClipboardExt;
+
+    app.clipboard()
+        .write_text(text)
+        .map_err(|e| format!("Failed to write to clipboard: {}", e))
+}
+
+/// Returns the clipboard text, e.g. to decide whether "paste" should be enabled.
+#[tauri::command]
+async fn read_clipboard(app: tauri::AppHandle) -> Result<String, String> {
+    use tauri_plugin_clipboard_manager::ClipboardExt;
+
+    app.clipboard()
+        .read_text()
+        .map_err(|e| format!("Failed to read clipboard: {}", e))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MenuItemSpec {
+    pub id: String,
+    pub label: String,
+    #[serde(default)]
+    pub accelerator: Option<String>,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MenuEntry {
+    Item(MenuItemSpec),
+    Separator,
+}
+
+/// Pops up a context menu built from `entries`. The chosen id comes back
+/// through the "menu-action" event.
+fn popup_menu_entries(window: &tauri::Window, entries: &[MenuEntry]) -> Result<(), String> {
+    let mut menu = MenuBuilder::new(window);
+
+    for entry in entries {
+        match entry {
+            MenuEntry::Item(spec) => {
+                // "tray-" ids are handled by the tray and never reach the frontend
+                if spec.id.starts_with("tray-") {
+                    return Err(format!("Reserved menu item id: {}", spec.id));
+                }
+
+                let mut builder = MenuItemBuilder::with_id(spec.id.as_str(), &spec.label).enabled(spec.enabled);
+                if let Some(accelerator) = &spec.accelerator {
+                    builder = builder.accelerator(accelerator);
+                }
+                let item = builder.build(window).map_err(|e| e.to_string())?;
+                menu = menu.item(&item);
+            }
+            MenuEntry::Separator => menu = menu.separator(),
+        }
+    }
+
+    let menu = menu.build().map_err(|e| e.to_string())?;
+    window.popup_menu(&menu).map_err(|e| e.to_string())
+}
+
+/// Built-in labels for the edit menu, in copy/cut/paste/inspect order.
+/// Unknown locales fall back to English.
+fn edit_menu_labels(locale: Option<&str>) -> [&'static str; 4] {
+    let language = locale
+        .and_then(|l| l.split(['-', '_']).next())
+        .map(str::to_ascii_lowercase);
+
+    match language.as_deref() {
+        Some("zh") => ["复制", "剪切", "粘贴", "检查"],
+        _ => ["Copy", "Cut", "Paste", "Inspect"],
+    }
+}
+
+fn edit_menu_item(id: &str, label: &str, accelerator: Option<&str>, enabled: bool) -> MenuEntry {
+    MenuEntry::Item(MenuItemSpec {
+        id: id.to_string(),
+        label: label.to_string(),
+        accelerator: accelerator.map(str::to_string),
+        enabled,
+    })
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn show_native_menu(
+    window: tauri::Window,
+    has_selection: bool,
+    can_copy: bool,
+    can_cut: bool,
+    has_clipboard: bool,
+    is_in_chat: bool,
+    locale: Option<String>,
+    labels: Option<HashMap<String, String>>,
+) -> Result<(), String> {
+    let [copy, cut, paste, inspect] = edit_menu_labels(locale.as_deref());
+    let labels = labels.unwrap_or_default();
+    let label = |id: &str, default: &'static str| labels.get(id).map(String::as_str).unwrap_or(default).to_string();
+
+    let entries = [
+        edit_menu_item("copy", &label("copy", copy), Some("CmdOrCtrl+C"), has_selection && can_copy),
+        edit_menu_item("cut", &label("cut", cut), Some("CmdOrCtrl+X"), can_cut && has_selection),
+        edit_menu_item("paste", &label("paste", paste), Some("CmdOrCtrl+V"), has_clipboard && !is_in_chat),
+        MenuEntry::Separator,
+        edit_menu_item("inspect", &label("inspect", inspect), None, true),
+    ];
+
+    popup_menu_entries(&window, &entries)
+}
+
+#[tauri::command]
+async fn show_context_menu(window: tauri::Window, items: Vec<MenuEntry>) -> Result<(), String> {
+    popup_menu_entries(&window, &items)
+}
+
+#[tauri::command]
+async fn run_code_as_file(
+    content: String,
+    extension: String,
+    recent: tauri::State<'_, RecentCodeFiles>,
+) -> Result<(), String> {
+    let file_path = write_temp_code_file(&content, &extension)?;
+    recent.push(file_path.clone(), &extension)?;
+
+    open_with_system(&file_path)
+}
+
+/// Hands a file to the OS default application for its type.
+fn open_with_system(file_path: &Path) -> Result<(), String> {
+    #[cfg(target_os = "windows")]
+    {
+        std::process::Command::new("cmd")
+            .args(&["/C", "start", "", &file_path.to_string_lossy()])
+            .spawn()
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+    }
+    #[cfg(target_os = "macos")]
+    {
+        std::process::Command::new("open")
+            .arg(file_path)
+            .spawn()
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        std::process::Command::new("xdg-open")
+            .arg(file_path)
+            .spawn()
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+    }
+
+    Ok(())
+}
+
+const MAX_RECENT_CODE_FILES: usize = 20;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentCodeFile {
+    pub path: PathBuf,
+    pub language: String,
+    pub created_at: u64,
+}
+
+/// Files written by `run_code_as_file`, newest first. Doubles as the
+/// allow-list for `open_recent_code_file`.
+#[derive(Default)]
+struct RecentCodeFiles(Mutex<VecDeque<RecentCodeFile>>);
+
+impl RecentCodeFiles {
+    fn push(&self, path: PathBuf, extension: &str) -> Result<(), String> {
+        let language = language_for_extension(extension)
+            .map(|(language, _)| language.to_string())
+            .unwrap_or_else(|| extension.trim().trim_start_matches('.').to_ascii_lowercase());
+        let created_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let mut files = self.0.lock().map_err(|e| format!("Lock error: {}", e))?;
+        files.retain(|f| f.path != path);
+        files.push_front(RecentCodeFile { path, language, created_at });
+        files.truncate(MAX_RECENT_CODE_FILES);
+        Ok(())
+    }
+
+    /// Drops entries whose file has since been deleted and returns the rest.
+    fn prune(&self) -> Result<Vec<RecentCodeFile>, String> {
+        let mut files = self.0.lock().map_err(|e| format!("Lock error: {}", e))?;
+        files.retain(|f| f.path.is_file());
+        Ok(files.iter().cloned().collect())
+    }
+}
+
+#[tauri::command]
+async fn list_recent_code_files(recent: tauri::State<'_, RecentCodeFiles>) -> Result<Vec<RecentCodeFile>, String> {
+    recent.prune()
+}
+
+/// Reopens a file from `list_recent_code_files`. Any other path is
+/// refused, so this can't be used to open arbitrary files.
+#[tauri::command]
+async fn open_recent_code_file(path: String, recent: tauri::State<'_, RecentCodeFiles>) -> Result<(), String> {
+    let path = PathBuf::from(path);
+    let known = recent.prune()?.into_iter().any(|f| f.path == path);
+    if !known {
+        return Err(format!("'{}' is not a recent code file", path.display()));
+    }
+
+    open_with_system(&path)
+}
+
+fn write_temp_code_file(content: &str, extension: &str) -> Result<PathBuf, String> {
+    let file_name = format!("neox-code-{}.{}", uuid::Uuid::new_v4(), extension);
+    let file_path = std::env::temp_dir().join(file_name);
+
+    std::fs::write(&file_path, content).map_err(|e| format!("Failed to write temp file: {}", e))?;
 
-        match chunk_result {
-            Ok(chunk) => {
-                stream_content.push_str(&chunk);
+    Ok(file_path)
+}
 
-                // Emit chunk to frontend
-                #[cfg(debug_assertions)]
-                eprintln!("Emitting chunk: [{} bytes] '{}'", chunk.len(), chunk);
-                let _ = window.emit(&format!("stream-{}", stream_id), chunk.clone());
-            }
-            Err(e) => {
-                eprintln!("Stream error: {}", e);
-                let _ = window.emit(&format!("stream-error-{}", stream_id), &e.to_string());
-                break;
+const DEFAULT_CODE_RUN_TIMEOUT_SECS: u64 = 30;
+
+/// Interpreter command and file extension for a code block's language id.
+fn interpreter_for(language: &str) -> Option<(&'static str, &'static [&'static str], &'static str)> {
+    let python = if cfg!(target_os = "windows") { "python" } else { "python3" };
+
+    match language.trim().to_ascii_lowercase().as_str() {
+        "python" | "py" | "python3" => Some((python, &[], "py")),
+        "javascript" | "js" | "node" => Some(("node", &[], "js")),
+        "bash" | "sh" | "shell" | "zsh" => Some(("bash", &[], "sh")),
+        "ruby" | "rb" => Some(("ruby", &[], "rb")),
+        "php" => Some(("php", &[], "php")),
+        "lua" => Some(("lua", &[], "lua")),
+        "powershell" | "ps1" | "pwsh" => Some(("pwsh", &["-File"], "ps1")),
+        _ => None,
+    }
+}
+
+/// Language id, file extension and the snippets that suggest it, each with
+/// a weight. Ids match what `interpreter_for` understands where one exists.
+type LanguageHint = (&'static str, &'static str, &'static [(&'static str, u32)]);
+
+const LANGUAGE_HINTS: &[LanguageHint] = &[
+    ("python", "py", &[("def ", 2), ("import ", 1), ("elif ", 3), ("print(", 1), ("self.", 2), ("__name__", 3), ("):\n", 2), ("None", 1)]),
+    ("javascript", "js", &[("const ", 2), ("let ", 1), ("function ", 1), ("=> ", 2), ("console.log", 3), ("require(", 2), ("===", 3), ("document.", 2)]),
+    ("typescript", "ts", &[("interface ", 2), (": string", 3), (": number", 3), ("console.log", 1), ("export type ", 3), ("=> ", 1), ("const ", 1)]),
+    ("bash", "sh", &[("echo ", 1), ("fi\n", 3), ("then\n", 2), ("$(", 2), ("esac", 3), ("done\n", 2), ("${", 1), ("[[ ", 2)]),
+    ("ruby", "rb", &[("puts ", 3), ("end\n", 1), (" do |", 3), ("require '", 2), ("attr_accessor", 3), ("elsif ", 3), ("def ", 1)]),
+    ("php", "php", &[("<?php", 6), ("$this->", 3), ("echo ", 1), ("function ", 1), ("->", 1)]),
+    ("lua", "lua", &[("local ", 3), ("function ", 1), ("end\n", 1), ("~=", 3), ("elseif ", 2), ("then\n", 1), ("..", 1)]),
+    ("powershell", "ps1", &[("Write-Host", 4), ("Write-Output", 4), ("Get-", 2), ("param(", 2), ("$PS", 3), ("-eq ", 2)]),
+    ("rust", "rs", &[("fn ", 2), ("let mut ", 3), ("impl ", 3), ("println!", 4), ("pub fn ", 3), ("::", 1), ("use std::", 4)]),
+    ("go", "go", &[("package ", 3), ("func ", 3), (":= ", 2), ("fmt.", 3), ("import (", 3)]),
+    ("c", "c", &[("#include <", 3), ("printf(", 2), ("int main(", 3), ("->", 1), ("malloc(", 3)]),
+    ("cpp", "cpp", &[("#include <iostream>", 5), ("std::", 3), ("cout <<", 4), ("template <", 3), ("namespace ", 2)]),
+    ("java", "java", &[("public class ", 4), ("System.out.", 4), ("public static void main", 5), ("private ", 1), ("import java.", 5)]),
+    ("html", "html", &[("<!DOCTYPE", 5), ("<html", 4), ("<div", 2), ("</", 1), ("<body", 3)]),
+    ("sql", "sql", &[("SELECT ", 3), (" FROM ", 2), ("INSERT INTO ", 4), ("CREATE TABLE ", 4), (" WHERE ", 2)]),
+];
+
+/// Guesses below this are reported as plain text.
+const MIN_LANGUAGE_CONFIDENCE: f32 = 0.3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LanguageGuess {
+    pub language: String,
+    /// Suitable for `run_code_as_file`.
+    pub extension: String,
+    /// 0 to 1; 1 only for certain signals like a shebang or extension hint.
+    pub confidence: f32,
+}
+
+fn language_for_extension(extension: &str) -> Option<(&'static str, &'static str)> {
+    let extension = extension.trim().trim_start_matches('.').to_ascii_lowercase();
+    let extension = match extension.as_str() {
+        "bash" | "zsh" => "sh",
+        "mjs" | "cjs" | "jsx" => "js",
+        "tsx" => "ts",
+        "htm" => "html",
+        "cc" | "cxx" | "hpp" => "cpp",
+        "h" => "c",
+        "python" => "py",
+        other => other,
+    };
+    if extension == "json" {
+        return Some(("json", "json"));
+    }
+    LANGUAGE_HINTS
+        .iter()
+        .find(|(_, ext, _)| *ext == extension)
+        .map(|(language, ext, _)| (*language, *ext))
+}
+
+/// The interpreter named in a `#!` line, e.g. `#!/usr/bin/env python3`.
+fn language_for_shebang(code: &str) -> Option<(&'static str, &'static str)> {
+    let shebang = code.lines().next()?.strip_prefix("#!")?;
+    let mut parts = shebang.split_whitespace();
+    let mut program = parts.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = parts.find(|part| !part.starts_with('-'))?;
+    }
+    let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    match program {
+        "python" => Some(("python", "py")),
+        "node" | "deno" | "bun" => Some(("javascript", "js")),
+        "bash" | "sh" | "zsh" | "dash" | "ksh" => Some(("bash", "sh")),
+        "ruby" => Some(("ruby", "rb")),
+        "php" => Some(("php", "php")),
+        "lua" | "luajit" => Some(("lua", "lua")),
+        "pwsh" | "powershell" => Some(("powershell", "ps1")),
+        _ => None,
+    }
+}
+
+/// Best-guess language of a code block, from (in order) an extension hint,
+/// a shebang, JSON syntax and finally keyword frequency. Falls back to
+/// "txt" when nothing stands out.
+#[tauri::command]
+async fn detect_code_language(code: String, extension_hint: Option<String>) -> Result<LanguageGuess, String> {
+    let guess = |language: &str, extension: &str, confidence: f32| {
+        Ok(LanguageGuess {
+            language: language.to_string(),
+            extension: extension.to_string(),
+            confidence,
+        })
+    };
+
+    if let Some((language, extension)) = extension_hint.as_deref().and_then(language_for_extension) {
+        return guess(language, extension, 1.0);
+    }
+    if let Some((language, extension)) = language_for_shebang(&code) {
+        return guess(language, extension, 1.0);
+    }
+
+    let trimmed = code.trim();
+    if (trimmed.starts_with('{') || trimmed.starts_with('[')) && serde_json::from_str::<serde_json::Value>(trimmed).is_ok() {
+        return guess("json", "json", 0.95);
+    }
+
+    let mut scores: Vec<(u32, &str, &str)> = LANGUAGE_HINTS
+        .iter()
+        .map(|(language, extension, hints)| {
+            // Capped per hint so one repeated token can't decide alone
+            let score = hints
+                .iter()
+                .map(|(needle, weight)| code.matches(needle).count().min(3) as u32 * weight)
+                .sum();
+            (score, *language, *extension)
+        })
+        .collect();
+    scores.sort_by_key(|score| std::cmp::Reverse(score.0));
+
+    let (best, language, extension) = scores[0];
+    let runner_up = scores.get(1).map_or(0, |s| s.0);
+    // High when the winner is both well supported and clearly ahead
+    let margin = (best - runner_up) as f32 / best.max(1) as f32;
+    let support = (best as f32 / 8.0).min(1.0);
+    let confidence = (0.5 * margin + 0.5 * support) * support;
+
+    if confidence < MIN_LANGUAGE_CONFIDENCE {
+        return guess("txt", "txt", 0.0);
+    }
+    guess(language, extension, (confidence * 100.0).round() / 100.0)
+}
+
+/// How long a cancelled run gets to exit after SIGTERM before SIGKILL.
+const CODE_RUN_KILL_GRACE: std::time::Duration = std::time::Duration::from_secs(2);
+/// How long output is still read once a run is over. A process that left
+/// the group can keep the pipes open indefinitely, so it is cut off.
+const CODE_RUN_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Builds the interpreter process. On Unix it leads its own process group,
+/// so everything it starts can be signalled together.
+fn interpreter_command(program: &str, args: &[&str], file_path: &Path) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(program);
+    command
+        .args(args)
+        .arg(file_path)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    #[cfg(unix)]
+    command.process_group(0);
+    command
+}
+
+/// Terminates a run together with any processes it started. Killing only
+/// the interpreter would leave e.g. a `start`ed program or a shell's
+/// background jobs running. Also called after a normal exit, to clean up
+/// jobs the script left behind; `pid` is taken at spawn for that, since
+/// `child.id()` is gone once the child has been waited on.
+async fn kill_process_tree(child: &mut tokio::process::Child, pid: Option<u32>) {
+    #[cfg(unix)]
+    if let Some(pid) = pid {
+        let group = -(pid as libc::pid_t);
+        // SAFETY: kill() has no memory-safety preconditions; the negative
+        // pid addresses the group the child was spawned to lead
+        unsafe { libc::kill(group, libc::SIGTERM) };
+        let _ = tokio::time::timeout(CODE_RUN_KILL_GRACE, child.wait()).await;
+        // Children that ignored SIGTERM are still in the group
+        unsafe { libc::kill(group, libc::SIGKILL) };
+    }
+
+    // An exited leader's pid may already belong to an unrelated process
+    #[cfg(target_os = "windows")]
+    if let (Some(pid), Ok(None)) = (pid, child.try_wait()) {
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        let _ = tokio::process::Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .creation_flags(CREATE_NO_WINDOW)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await;
+    }
+
+    let _ = child.kill().await;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CodeRunResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the process was killed (timeout or signal).
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+/// Reads a pipe to the end, or until `stop` fires, keeping what it got.
+async fn read_pipe<R: tokio::io::AsyncRead + Unpin>(pipe: Option<R>, stop: CancellationToken) -> String {
+    use tokio::io::AsyncReadExt;
+
+    let mut output = Vec::new();
+    if let Some(mut pipe) = pipe {
+        let mut buf = [0u8; 8192];
+        loop {
+            tokio::select! {
+                read = pipe.read(&mut buf) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => output.extend_from_slice(&buf[..n]),
+                },
+                _ = stop.cancelled() => break,
             }
         }
     }
+    String::from_utf8_lossy(&output).into_owned()
+}
 
-    // Clean up
-    {
-        let mut map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
-        map.remove(&stream_id);
+/// Waits for an output reader, telling it to stop once the drain timeout
+/// passes.
+async fn drain_reader<T>(mut reader: tauri::async_runtime::JoinHandle<T>, stop: &CancellationToken) -> Option<T> {
+    match tokio::time::timeout(CODE_RUN_DRAIN_TIMEOUT, &mut reader).await {
+        Ok(result) => result.ok(),
+        Err(_) => {
+            stop.cancel();
+            reader.await.ok()
+        }
     }
+}
 
-    Ok(stream_id)
+#[tauri::command]
+async fn run_code_with_interpreter(
+    content: String,
+    language: String,
+    timeout_secs: Option<u64>,
+) -> Result<CodeRunResult, String> {
+    let (program, args, extension) = interpreter_for(&language)
+        .ok_or_else(|| format!("No interpreter configured for language: {}", language))?;
+    let timeout_secs = match timeout_secs {
+        Some(0) => return Err("Invalid timeout: timeout_secs must be greater than 0".to_string()),
+        Some(secs) => secs,
+        None => DEFAULT_CODE_RUN_TIMEOUT_SECS,
+    };
+
+    let file_path = write_temp_code_file(&content, extension)?;
+
+    let mut child = match interpreter_command(program, args, &file_path).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            let _ = std::fs::remove_file(&file_path);
+            return Err(format!("Failed to start {}: {}", program, e));
+        }
+    };
+    let pid = child.id();
+
+    let stop_reading = CancellationToken::new();
+    let stdout = tauri::async_runtime::spawn(read_pipe(child.stdout.take(), stop_reading.clone()));
+    let stderr = tauri::async_runtime::spawn(read_pipe(child.stderr.take(), stop_reading.clone()));
+
+    let status = tokio::time::timeout(std::time::Duration::from_secs(timeout_secs), child.wait()).await;
+    kill_process_tree(&mut child, pid).await;
+    let stdout = drain_reader(stdout, &stop_reading).await.unwrap_or_default();
+    let mut stderr = drain_reader(stderr, &stop_reading).await.unwrap_or_default();
+
+    let _ = std::fs::remove_file(&file_path);
+
+    match status {
+        Ok(Ok(status)) => Ok(CodeRunResult {
+            stdout,
+            stderr,
+            exit_code: status.code(),
+            timed_out: false,
+        }),
+        Ok(Err(e)) => Err(format!("Failed to run {}: {}", program, e)),
+        Err(_) => {
+            if !stderr.is_empty() && !stderr.ends_with('\n') {
+                stderr.push('\n');
+            }
+            stderr.push_str(&format!("Process killed after {}s timeout", timeout_secs));
+            Ok(CodeRunResult {
+                stdout,
+                stderr,
+                exit_code: None,
+                timed_out: true,
+            })
+        }
+    }
+}
+
+/// Cancellation tokens for in-flight `run_code_streaming` processes.
+#[derive(Default)]
+struct CodeRunMap(Mutex<HashMap<String, CancellationToken>>);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CodeOutput {
+    /// "stdout" or "stderr"
+    pub stream: &'static str,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CodeExit {
+    pub exit_code: Option<i32>,
+    pub cancelled: bool,
+    pub timed_out: bool,
+}
+
+fn forward_output_lines<R>(
+    reader: R,
+    window: tauri::Window,
+    event: String,
+    stream: &'static str,
+    stop: CancellationToken,
+) -> tauri::async_runtime::JoinHandle<()>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    tauri::async_runtime::spawn(async move {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        loop {
+            let line = tokio::select! {
+                line = lines.next_line() => line,
+                _ = stop.cancelled() => break,
+            };
+            let Ok(Some(line)) = line else {
+                break;
+            };
+            let _ = window.emit(&event, CodeOutput { stream, line });
+        }
+    })
 }
 
+/// Like `run_code_with_interpreter`, but emits each output line as a
+/// `code-output-{run_id}` event and a final `code-exit-{run_id}`.
 #[tauri::command]
-async fn scan_ollama_models(
-    api_endpoint: String,
-    api_key: Option<String>,
-) -> Result<Vec<String>, String> {
-    proxy_scan_ollama_models(api_endpoint, api_key).await
+async fn run_code_streaming(
+    content: String,
+    language: String,
+    window: tauri::Window,
+    run_id: Option<String>,
+    timeout_secs: Option<u64>,
+    runs: tauri::State<'_, CodeRunMap>,
+) -> Result<String, String> {
+    let (program, args, extension) = interpreter_for(&language)
+        .ok_or_else(|| format!("No interpreter configured for language: {}", language))?;
+    let timeout_secs = match timeout_secs {
+        Some(0) => return Err("Invalid timeout: timeout_secs must be greater than 0".to_string()),
+        Some(secs) => secs,
+        None => DEFAULT_CODE_RUN_TIMEOUT_SECS,
+    };
+    let run_id = run_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let file_path = write_temp_code_file(&content, extension)?;
+
+    let mut child = match interpreter_command(program, args, &file_path).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            let _ = std::fs::remove_file(&file_path);
+            return Err(format!("Failed to start {}: {}", program, e));
+        }
+    };
+    let pid = child.id();
+
+    let token = CancellationToken::new();
+    {
+        let mut map = runs.0.lock().map_err(|e| format!("Lock error: {}", e))?;
+        map.insert(run_id.clone(), token.clone());
+    }
+
+    let event = format!("code-output-{}", run_id);
+    let stop_reading = CancellationToken::new();
+    let readers = [
+        child
+            .stdout
+            .take()
+            .map(|out| forward_output_lines(out, window.clone(), event.clone(), "stdout", stop_reading.clone())),
+        child
+            .stderr
+            .take()
+            .map(|err| forward_output_lines(err, window.clone(), event.clone(), "stderr", stop_reading.clone())),
+    ];
+
+    let mut timed_out = false;
+    let status = tokio::select! {
+        status = child.wait() => status.ok(),
+        _ = token.cancelled() => None,
+        _ = tokio::time::sleep(std::time::Duration::from_secs(timeout_secs)) => {
+            timed_out = true;
+            None
+        }
+    };
+
+    kill_process_tree(&mut child, pid).await;
+
+    // Drain whatever the readers still have buffered before reporting the exit
+    for reader in readers.into_iter().flatten() {
+        drain_reader(reader, &stop_reading).await;
+    }
+
+    if let Ok(mut map) = runs.0.lock() {
+        map.remove(&run_id);
+    }
+    let _ = std::fs::remove_file(&file_path);
+
+    let _ = window.emit(&format!("code-exit-{}", run_id), CodeExit {
+        exit_code: status.and_then(|s| s.code()),
+        cancelled: token.is_cancelled(),
+        timed_out,
+    });
+
+    Ok(run_id)
 }
 
 #[tauri::command]
-async fn cancel_stream(
-    stream_id: String,
-    token_map: tauri::State<'_, TokenMap>,
+async fn cancel_code_run(
+    run_id: String,
+    runs: tauri::State<'_, CodeRunMap>,
 ) -> Result<bool, String> {
-    let mut map = token_map.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let mut map = runs.0.lock().map_err(|e| format!("Lock error: {}", e))?;
 
-    if let Some(ctx) = map.get(&stream_id) {
-        ctx.token.cancel();
-        map.remove(&stream_id);
+    if let Some(token) = map.remove(&run_id) {
+        token.cancel();
         Ok(true)
     } else {
         Ok(false)
     }
 }
 
-#[tauri::command]
-async fn open_devtools(app: tauri::AppHandle) -> Result<(), String> {
-    if let Some(webview_window) = app.get_webview_window("main") {
-        webview_window.open_devtools();
-        Ok(())
-    } else {
-        Err("Failed to get webview window".to_string())
+fn show_main_window(app: &tauri::AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
     }
 }
 
+const GLOBAL_SHORTCUT_KEY: &str = "global_shortcut";
+const DEFAULT_GLOBAL_SHORTCUT: &str = "CommandOrControl+Shift+Space";
+
+/// Accelerator currently bound to toggling the main window, if any.
+#[derive(Default)]
+struct GlobalShortcutState(Mutex<Option<String>>);
+
+/// Hides the main window if it is in front, otherwise brings it forward.
+fn toggle_main_window(app: &tauri::AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let in_front = window.is_visible().unwrap_or(false)
+            && window.is_focused().unwrap_or(false)
+            && !window.is_minimized().unwrap_or(false);
+        if in_front {
+            let _ = window.hide();
+            return;
+        }
+    }
+    show_main_window(app);
+}
+
+fn register_global_shortcut(app: &tauri::AppHandle, accelerator: &str) -> Result<(), String> {
+    use tauri_plugin_global_shortcut::{GlobalShortcutExt, Shortcut, ShortcutState};
+
+    let shortcut: Shortcut = accelerator
+        .parse()
+        .map_err(|e| format!("Invalid shortcut '{}': {}", accelerator, e))?;
+
+    app.global_shortcut()
+        .on_shortcut(shortcut, |app, _shortcut, event| {
+            if event.state == ShortcutState::Pressed {
+                toggle_main_window(app);
+            }
+        })
+        .map_err(|e| {
+            format!(
+                "Failed to register shortcut '{}': {}. It may already be in use by another application",
+                accelerator, e
+            )
+        })
+}
+
+/// The saved shortcut, where an empty string means the user turned it off.
+fn load_global_shortcut(app: &tauri::AppHandle) -> Option<String> {
+    let saved = app
+        .store(STORE_FILE)
+        .ok()
+        .and_then(|store| store.get(GLOBAL_SHORTCUT_KEY))
+        .and_then(|value| value.as_str().map(str::to_string));
+
+    match saved {
+        Some(accelerator) if accelerator.is_empty() => None,
+        Some(accelerator) => Some(accelerator),
+        None => Some(DEFAULT_GLOBAL_SHORTCUT.to_string()),
+    }
+}
+
+/// Rebinds the show/hide shortcut. `None` or an empty string disables it.
+/// If the new shortcut can't be registered the previous one stays active.
 #[tauri::command]
-async fn manage_proxy_server(
-    enable: bool,
-    port: Option<u16>,
-    state: tauri::State<'_, ProxyState>,
+async fn set_global_shortcut(
+    app: tauri::AppHandle,
+    accelerator: Option<String>,
+    state: tauri::State<'_, GlobalShortcutState>,
 ) -> Result<(), String> {
-    let target_port = port.unwrap_or(8080);
+    use tauri_plugin_global_shortcut::GlobalShortcutExt;
 
-    // 1. Stop existing server if any
-    let existing_handle = {
-        let mut handle_lock = state.server_handle.lock().map_err(|e| e.to_string())?;
-        handle_lock.take()
+    let accelerator = accelerator.map(|a| a.trim().to_string()).filter(|a| !a.is_empty());
+    let mut current = state.0.lock().map_err(|e| e.to_string())?;
+
+    if *current != accelerator {
+        if let Some(old) = current.as_deref() {
+            let _ = app.global_shortcut().unregister(old);
+        }
+        if let Some(new) = accelerator.as_deref() {
+            if let Err(e) = register_global_shortcut(&app, new) {
+                if let Some(old) = current.as_deref() {
+                    let _ = register_global_shortcut(&app, old);
+                }
+                return Err(e);
+            }
+        }
+        *current = accelerator.clone();
+    }
+
+    let store = app.store(STORE_FILE).map_err(|e| e.to_string())?;
+    store.set(GLOBAL_SHORTCUT_KEY, accelerator.unwrap_or_default());
+    store.save().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_global_shortcut(state: tauri::State<'_, GlobalShortcutState>) -> Result<Option<String>, String> {
+    Ok(state.0.lock().map_err(|e| e.to_string())?.clone())
+}
+
+fn main_window(app: &tauri::AppHandle) -> Result<tauri::WebviewWindow, String> {
+    app.get_webview_window("main")
+        .ok_or_else(|| "Main window not found".to_string())
+}
+
+#[tauri::command]
+async fn set_always_on_top(app: tauri::AppHandle, enabled: bool) -> Result<(), String> {
+    main_window(&app)?
+        .set_always_on_top(enabled)
+        .map_err(|e| format!("Failed to set always-on-top: {}", e))
+}
+
+/// Below this the window is practically invisible and hard to get back.
+const MIN_WINDOW_OPACITY: f64 = 0.2;
+
+/// Sets the main window's opacity, clamped to [0.2, 1.0]. Returns the value
+/// actually applied.
+#[tauri::command]
+async fn set_window_opacity(app: tauri::AppHandle, opacity: f64) -> Result<f64, String> {
+    if !opacity.is_finite() {
+        return Err(format!("Invalid opacity: {}", opacity));
+    }
+    let opacity = opacity.clamp(MIN_WINDOW_OPACITY, 1.0);
+
+    apply_window_opacity(&main_window(&app)?, opacity)?;
+
+    Ok(opacity)
+}
+
+#[cfg(target_os = "linux")]
+fn apply_window_opacity(window: &tauri::WebviewWindow, opacity: f64) -> Result<(), String> {
+    use gtk::prelude::WidgetExt;
+
+    // GTK objects may only be touched from the main thread
+    let target = window.clone();
+    window
+        .run_on_main_thread(move || match target.gtk_window() {
+            Ok(gtk_window) => gtk_window.set_opacity(opacity),
+            Err(e) => eprintln!("Failed to set window opacity: {}", e),
+        })
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(all(target_os = "windows", target_pointer_width = "64"))]
+fn apply_window_opacity(window: &tauri::WebviewWindow, opacity: f64) -> Result<(), String> {
+    use windows::Win32::Foundation::COLORREF;
+    use windows::Win32::UI::WindowsAndMessaging::{
+        GetWindowLongPtrW, SetLayeredWindowAttributes, SetWindowLongPtrW, GWL_EXSTYLE, LWA_ALPHA, WS_EX_LAYERED,
     };
 
-    if let Some(h) = existing_handle {
-        h.stop(true).await;
-        println!("HTTP proxy server stopped");
+    let hwnd = window.hwnd().map_err(|e| e.to_string())?;
+    let alpha = (opacity * 255.0).round() as u8;
+
+    // SAFETY: hwnd is the live handle of our own window
+    unsafe {
+        let style = GetWindowLongPtrW(hwnd, GWL_EXSTYLE);
+        SetWindowLongPtrW(hwnd, GWL_EXSTYLE, style | WS_EX_LAYERED.0 as isize);
+        SetLayeredWindowAttributes(hwnd, COLORREF(0), alpha, LWA_ALPHA)
+            .map_err(|e| format!("Failed to set window opacity: {}", e))
     }
+}
 
-    // 2. Start new server if enabled
-    if enable {
-        let server = HttpServer::new(|| {
-            App::new()
-                .wrap(
-                    Cors::default()
-                        .allow_any_origin()
-                        .allow_any_method()
-                        .allow_any_header()
-                        .supports_credentials()
-                        .max_age(3600),
-                )
-                .route("/chat", web::post().to(handle_chat))
-                .route("/chat/stream", web::post().to(handle_chat_stream))
-                .route("/models", web::post().to(handle_models))
+#[cfg(target_os = "macos")]
+fn apply_window_opacity(window: &tauri::WebviewWindow, opacity: f64) -> Result<(), String> {
+    // Raw pointers aren't Send; AppKit calls must run on the main thread
+    let ns_window = window.ns_window().map_err(|e| e.to_string())? as usize;
+    window
+        .run_on_main_thread(move || {
+            // SAFETY: ns_window is the NSWindow backing our own window
+            let ns_window = unsafe { &*(ns_window as *const objc2_app_kit::NSWindow) };
+            ns_window.setAlphaValue(opacity);
         })
-        .bind(format!("127.0.0.1:{}", target_port))
-        .map_err(|e| format!("Failed to bind HTTP server to port {}: {}", target_port, e))?
-        .run();
+        .map_err(|e| e.to_string())
+}
 
-        let handle = server.handle();
-        tauri::async_runtime::spawn(async move {
-            println!("HTTP proxy server running on http://127.0.0.1:{}", target_port);
-            if let Err(e) = server.await {
-                eprintln!("HTTP server error: {}", e);
+#[cfg(not(any(
+    target_os = "linux",
+    all(target_os = "windows", target_pointer_width = "64"),
+    target_os = "macos"
+)))]
+fn apply_window_opacity(_window: &tauri::WebviewWindow, _opacity: f64) -> Result<(), String> {
+    Err("Window opacity is not supported on this platform".to_string())
+}
+
+fn build_tray(app: &tauri::App) -> tauri::Result<()> {
+    let show = MenuItemBuilder::with_id("tray-show", "Show").build(app)?;
+    let hide = MenuItemBuilder::with_id("tray-hide", "Hide").build(app)?;
+    let quit = MenuItemBuilder::with_id("tray-quit", "Quit").build(app)?;
+
+    let menu = MenuBuilder::new(app)
+        .item(&show)
+        .item(&hide)
+        .separator()
+        .item(&quit)
+        .build()?;
+
+    let mut tray = TrayIconBuilder::with_id("main-tray")
+        .tooltip("Miscuay")
+        .menu(&menu)
+        .show_menu_on_left_click(false)
+        .on_menu_event(|app, event| match event.id().as_ref() {
+            "tray-show" => show_main_window(app),
+            "tray-hide" => {
+                if let Some(window) = app.get_webview_window("main") {
+                    let _ = window.hide();
+                }
+            }
+            "tray-quit" => {
+                let app = app.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = stop_proxy_server(&app.state::<ProxyState>()).await {
+                        eprintln!("Failed to stop HTTP proxy server: {}", e);
+                    }
+                    app.exit(0);
+                });
+            }
+            _ => {}
+        })
+        .on_tray_icon_event(|tray, event| {
+            if let TrayIconEvent::Click {
+                button: MouseButton::Left,
+                button_state: MouseButtonState::Up,
+                ..
+            } = event
+            {
+                show_main_window(tray.app_handle());
             }
         });
 
-        let mut handle_lock = state.server_handle.lock().map_err(|e| e.to_string())?;
-        *handle_lock = Some(handle);
+    if let Some(icon) = app.default_window_icon() {
+        tray = tray.icon(icon.clone());
     }
 
+    tray.build(app)?;
     Ok(())
 }
 
-async fn proxy_scan_ollama_models(
-    api_endpoint: String,
-    api_key: Option<String>,
-) -> Result<Vec<String>, String> {
-    let url = url::Url::parse(&api_endpoint)
-        .map_err(|e| format!("Invalid API endpoint URL: {}", e))?;
-
-    let tags_url = format!(
-        "{}://{}{}",
-        url.scheme(),
-        url.host_str().ok_or("Missing host")?,
-        "/api/tags"
-    );
-
-    let client = build_http_client(30)?;
+/// Window shell behaviour (F11 fullscreen, reload blocking), injected on
+/// every page load so it survives a reload.
+const SHELL_SCRIPT: &str = r#"
+(function () {
+  let isFullscreen = false;
+  const updateFullscreenState = () => {
+    if (window.__TAURI__) {
+      window.__TAURI__.window.getCurrentWindow().isFullscreen().then(f => {
+        isFullscreen = f;
+        document.body.classList.toggle('fullscreen-mode', f);
+      });
+    }
+  };
+  updateFullscreenState();
+  window.addEventListener('resize', updateFullscreenState);
 
-    let mut req_builder = client.get(&tags_url).header("Content-Type", "application/json");
+  function blockReload(e) {
+    const isCtrlOrMeta = e.ctrlKey || e.metaKey;
+    const isReload = e.key === 'F5' || (isCtrlOrMeta && (e.key === 'r' || e.key === 'R'));
+    if (isReload) {
+      e.preventDefault();
+      e.stopImmediatePropagation();
+    }
+  }
 
-    req_builder = add_auth_headers(req_builder, api_key);
+  window.__neoxSetReloadBlocking = function (enabled) {
+    window.removeEventListener('keydown', blockReload, { capture: true });
+    if (enabled) {
+      window.addEventListener('keydown', blockReload, { capture: true });
+    }
+  };
+  window.__neoxSetReloadBlocking(__BLOCK_RELOAD__);
 
-    let response = req_builder
-        .send()
-        .await
-        .map_err(|e| format!("Request failed: {}", e))?;
+  function handleKeyboardEvents(e) {
+    const key = e.key;
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Failed to read error response".to_string());
-        return Err(format!("API error: {} - {}", status, error_text));
+    if (key === 'F11') {
+      e.preventDefault();
+      e.stopImmediatePropagation();
+      if (window.__TAURI__) {
+        const win = window.__TAURI__.window.getCurrentWindow();
+        win.isFullscreen().then(f => {
+          win.setFullscreen(!f);
+          isFullscreen = !f;
+          document.body.classList.toggle('fullscreen-mode', !f);
+        });
+      }
+      return false;
     }
 
-    let data: OllamaTagsResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
-
-    let model_names: Vec<String> = data.models.into_iter().map(|m| m.name).collect();
-
-    Ok(model_names)
-}
-
-#[tauri::command]
-async fn show_native_menu(
-    window: tauri::Window,
-    has_selection: bool,
-    can_copy: bool,
-    can_cut: bool,
-    has_clipboard: bool,
-    is_in_chat: bool,
-) -> Result<(), String> {
+    if (key === 'Escape' && isFullscreen) {
+      e.preventDefault();
+    }
+    return true;
+  }
 
-    let copy = MenuItemBuilder::with_id("copy", "复制")
-        .accelerator("CmdOrCtrl+C")
-        .enabled(has_selection && can_copy)
-        .build(&window)
-        .map_err(|e| e.to_string())?;
-
-    let paste = MenuItemBuilder::with_id("paste", "粘贴")
-        .accelerator("CmdOrCtrl+V")
-        .enabled(has_clipboard && !is_in_chat)
-        .build(&window)
-        .map_err(|e| e.to_string())?;
-
-    let cut = MenuItemBuilder::with_id("cut", "剪切")
-        .accelerator("CmdOrCtrl+X")
-        .enabled(can_cut && has_selection)
-        .build(&window)
-        .map_err(|e| e.to_string())?;
-
-    let inspect = MenuItemBuilder::with_id("inspect", "检查")
-        .build(&window)
-        .map_err(|e| e.to_string())?;
-
-    let menu = MenuBuilder::new(&window)
-        .item(&copy)
-        .item(&cut)
-        .item(&paste)
-        .separator()
-        .item(&inspect)
-        .build()
-        .map_err(|e| e.to_string())?;
+  window.addEventListener('keydown', handleKeyboardEvents, { capture: true });
+})();
+"#;
 
-    window.popup_menu(&menu).map_err(|e| e.to_string())?;
+/// Whether F5 / Ctrl+R reloads are swallowed. Off in debug builds.
+struct ReloadBlocking(AtomicBool);
 
-    Ok(())
+fn shell_script(block_reload: bool) -> String {
+    SHELL_SCRIPT.replace("__BLOCK_RELOAD__", if block_reload { "true" } else { "false" })
 }
 
 #[tauri::command]
-async fn run_code_as_file(
-    content: String,
-    extension: String,
+async fn set_reload_blocking(
+    window: tauri::WebviewWindow,
+    enabled: bool,
+    state: tauri::State<'_, ReloadBlocking>,
 ) -> Result<(), String> {
-    let temp_dir = std::env::temp_dir();
-    let file_name = format!("neox-code-{}.{}", uuid::Uuid::new_v4(), extension);
-    let file_path = temp_dir.join(file_name);
-
-    std::fs::write(&file_path, content).map_err(|e| format!("Failed to write temp file: {}", e))?;
-
-    #[cfg(target_os = "windows")]
-    {
-        std::process::Command::new("cmd")
-            .args(&["/C", "start", "", &file_path.to_string_lossy()])
-            .spawn()
-            .map_err(|e| format!("Failed to open file: {}", e))?;
-    }
-    #[cfg(target_os = "macos")]
-    {
-        std::process::Command::new("open")
-            .arg(&file_path)
-            .spawn()
-            .map_err(|e| format!("Failed to open file: {}", e))?;
-    }
-    #[cfg(target_os = "linux")]
-    {
-        std::process::Command::new("xdg-open")
-            .arg(&file_path)
-            .spawn()
-            .map_err(|e| format!("Failed to open file: {}", e))?;
-    }
-
-    Ok(())
+    state.0.store(enabled, Ordering::Relaxed);
+    window
+        .eval(format!(
+            "window.__neoxSetReloadBlocking && window.__neoxSetReloadBlocking({});",
+            enabled
+        ))
+        .map_err(|e| e.to_string())
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -548,21 +7551,39 @@ pub fn run() {
     let token_map: TokenMap = Arc::new(Mutex::new(HashMap::new()));
     let proxy_state = ProxyState {
         server_handle: Mutex::new(None),
+        bound_addr: Mutex::new(None),
+        running_config: Mutex::new(None),
+        apply_lock: tokio::sync::Mutex::new(()),
+        logger: Arc::new(ProxyLogger::new()),
+        limiter: Arc::new(ConcurrencyLimiter::new()),
+        upstream: Arc::new(Mutex::new(None)),
+        streams: Arc::new(Mutex::new(HashMap::new())),
+        generation: AtomicUsize::new(0),
+    };
+    let proxy_routes = SharedProxyRoutes::default();
+    let http_state = HttpState {
+        client: build_http_client(proxy_routes.clone(), std::time::Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS))
+            .expect("failed to create HTTP client"),
+        proxy_routes,
+        provider_defaults: SharedProviderDefaults::default(),
+        connect_clients: Mutex::new(HashMap::new()),
     };
 
     tauri::Builder::default()
         .plugin(tauri_plugin_system_fonts::init())
         .plugin(tauri_plugin_store::Builder::default().build())
-        .plugin(tauri_plugin_prevent_default::init())
+        // Reload is left to the shell script so it can be toggled at runtime
+        .plugin(
+            tauri_plugin_prevent_default::Builder::new()
+                .with_flags(tauri_plugin_prevent_default::Flags::all().difference(tauri_plugin_prevent_default::Flags::RELOAD))
+                .build(),
+        )
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_clipboard_manager::init())
         .plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| {
-            if let Some(window) = app.get_webview_window("main") {
-                let _ = window.unminimize();
-                let _ = window.show();
-                let _ = window.set_focus();
-            }
+            show_main_window(app);
         }))
+        .plugin(tauri_plugin_global_shortcut::Builder::new().build())
         .plugin(
             tauri_plugin_window_state::Builder::new()
                 .with_state_flags(tauri_plugin_window_state::StateFlags::all())
@@ -570,79 +7591,789 @@ pub fn run() {
         )
         .manage(token_map)
         .manage(proxy_state)
+        .manage(http_state)
+        .manage(CodeRunMap::default())
+        .manage(StreamMemory::default())
+        .manage(RecentCodeFiles::default())
+        .manage(ScanCache::default())
+        .manage(IdempotencyCache::default())
+        .manage(GlobalShortcutState::default())
+        .manage(ConversationStoreLock::default())
+        .manage(ReloadBlocking(AtomicBool::new(!cfg!(debug_assertions))))
         .invoke_handler(tauri::generate_handler![
             send_chat_request,
             send_chat_stream,
+            stream_chat_to_file,
             cancel_stream,
+            pause_stream,
+            get_stream_memory_usage,
+            resume_stream,
+            get_moderation_config,
+            set_moderation_config,
+            set_outbound_proxy,
+            get_outbound_proxy,
+            get_provider_defaults,
+            set_provider_defaults,
+            cancel_all_streams,
+            cancel_streams_by_prefix,
+            list_active_streams,
             scan_ollama_models,
+            generate_embeddings,
+            validate_chat_config,
+            validate_api_key,
+            repair_markdown,
+            measure_endpoint_latency,
+            benchmark_model,
+            pull_ollama_model,
+            show_ollama_model,
+            delete_ollama_model,
+            set_ollama_keep_alive,
+            stop_ollama_model,
+            warmup_model,
             open_devtools,
             manage_proxy_server,
+            get_proxy_status,
+            set_proxy_logging,
+            get_proxy_log,
+            clear_proxy_log,
+            export_proxy_har,
+            set_proxy_concurrency_limit,
+            set_proxy_upstream,
+            get_proxy_upstream,
+            save_conversation,
+            load_conversation,
+            list_conversations,
+            export_conversation_markdown,
+            save_message_images,
+            open_external_url,
+            count_tokens,
+            trim_messages_to_budget,
+            prepare_messages_for_provider,
+            validate_model_options,
             show_native_menu,
+            show_context_menu,
+            copy_to_clipboard,
+            read_clipboard,
             run_code_as_file,
+            list_recent_code_files,
+            open_recent_code_file,
+            detect_code_language,
+            set_reload_blocking,
+            set_always_on_top,
+            set_window_opacity,
+            set_global_shortcut,
+            get_global_shortcut,
+            run_code_with_interpreter,
+            run_code_streaming,
+            cancel_code_run,
         ])
+        .on_page_load(|webview, payload| {
+            if payload.event() != tauri::webview::PageLoadEvent::Finished {
+                return;
+            }
+            let block_reload = webview.state::<ReloadBlocking>().0.load(Ordering::Relaxed);
+            if let Err(err) = webview.eval(shell_script(block_reload)) {
+                eprintln!("failed to inject shell script: {}", err);
+            }
+        })
+        .on_window_event(|window, event| {
+            if let tauri::WindowEvent::Destroyed = event {
+                let token_map = window.state::<TokenMap>();
+                if let Err(e) = cancel_all(&token_map) {
+                    eprintln!("Failed to cancel streams on close: {}", e);
+                }
+                if let Ok(mut runs) = window.state::<CodeRunMap>().0.lock() {
+                    for (_, token) in runs.drain() {
+                        token.cancel();
+                    }
+                }
+            }
+        })
         .setup(|app| {
             app.on_menu_event(|app_handle, event| {
                 let id = event.id().as_ref();
+                // Tray items are handled by the tray's own menu handler
+                if id.starts_with("tray-") {
+                    return;
+                }
                 let _ = app_handle.emit("menu-action", id);
             });
+            for file in [STORE_FILE, CONVERSATIONS_STORE_FILE] {
+                recover_corrupt_store(app.handle(), file);
+            }
+
+            build_tray(app)?;
+
+            match ProxyRoutes::from_config(&load_outbound_proxy_config(app.handle())) {
+                Ok(routes) => {
+                    if let Ok(mut current) = app.state::<HttpState>().proxy_routes.write() {
+                        *current = routes;
+                    }
+                }
+                Err(e) => eprintln!("Ignoring saved outbound proxy: {}", e),
+            }
+            if let Ok(mut current) = app.state::<HttpState>().provider_defaults.write() {
+                *current = load_provider_defaults(app.handle());
+            }
+
+            if let Some(accelerator) = load_global_shortcut(app.handle()) {
+                match register_global_shortcut(app.handle(), &accelerator) {
+                    Ok(()) => {
+                        if let Ok(mut current) = app.state::<GlobalShortcutState>().0.lock() {
+                            *current = Some(accelerator);
+                        }
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+
+            // Restore the proxy server if it was enabled on the last run
+            let app_handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let Some(config) = load_proxy_config(&app_handle) else {
+                    return;
+                };
+                if !config.enabled {
+                    return;
+                }
+
+                let state = app_handle.state::<ProxyState>();
+                let client = app_handle.state::<HttpState>().client.clone();
+                if let Err(e) = apply_proxy_server(&app_handle, &state, client, &config).await {
+                    eprintln!("Failed to restore HTTP proxy server: {}", e);
+                    let _ = app_handle.emit("proxy-server-error", e);
+                }
+            });
             if let Some(window) = app.get_webview_window("main") {
-                let script = r#"
-(function () {
-  let isFullscreen = false;
-  const updateFullscreenState = () => {
-    if (window.__TAURI__) {
-      window.__TAURI__.window.getCurrentWindow().isFullscreen().then(f => {
-        isFullscreen = f;
-        document.body.classList.toggle('fullscreen-mode', f);
-      });
+                let _ = window.set_decorations(false);
+            }
+
+            Ok(())
+        })
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // Release the proxy port before the process goes away so an
+            // immediate restart can bind it again
+            if let tauri::RunEvent::Exit = event {
+                let state = app.state::<ProxyState>();
+                if let Err(e) = tauri::async_runtime::block_on(stop_proxy_server(&state)) {
+                    eprintln!("Failed to stop HTTP proxy server: {}", e);
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ollama_api_url_keeps_non_standard_port() {
+        assert_eq!(
+            ollama_api_url("http://127.0.0.1:11434/v1", "/api/tags").unwrap(),
+            "http://127.0.0.1:11434/api/tags"
+        );
     }
-  };
-  updateFullscreenState();
-  window.addEventListener('resize', updateFullscreenState);
 
-  function handleKeyboardEvents(e) {
-    const key = e.key;
-    const isCtrlOrMeta = e.ctrlKey || e.metaKey;
+    fn message(role: &str, text: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: serde_json::Value::String(text.to_string()),
+            images: None,
+        }
+    }
 
-    const isReload = key === 'F5' || (isCtrlOrMeta && (key === 'r' || key === 'R'));
-    if (isReload) {
-      e.preventDefault();
-      e.stopImmediatePropagation();
-      return false;
+    #[test]
+    fn validate_images_rejects_corrupt_base64() {
+        let mut corrupt = message("user", "what is this?");
+        corrupt.images = Some(vec!["data:image/png;base64,iVBORw0KGgo@@not*base64".to_string()]);
+
+        let err = validate_images(&[corrupt], &ImageLimits::default()).unwrap_err();
+        assert!(err.contains("Image 1 in message 1 is invalid"), "{}", err);
     }
 
-    if (key === 'F11') {
-      e.preventDefault();
-      e.stopImmediatePropagation();
-      if (window.__TAURI__) {
-        const win = window.__TAURI__.window.getCurrentWindow();
-        win.isFullscreen().then(f => {
-          win.setFullscreen(!f);
-          isFullscreen = !f;
-          document.body.classList.toggle('fullscreen-mode', !f);
-        });
-      }
-      return false;
+    /// Runs an upstream body through the parser line by line, the way
+    /// `proxy_chat_stream` does, and returns what the client would receive.
+    fn reframe(body: &str) -> Vec<String> {
+        let mut parser = SseParser::default();
+        let mut out = Vec::new();
+        for line in body.split('\n') {
+            if let Some(event) = parser.feed(line) {
+                let (text, done) = render_stream_event(event);
+                out.push(text);
+                if done {
+                    break;
+                }
+            }
+        }
+        out
     }
 
-    if (key === 'Escape' && isFullscreen) {
-      e.preventDefault();
+    #[test]
+    fn openai_sse_body_is_reframed_with_event_names() {
+        let first = r#"{"id":"c1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"role":"assistant","content":"Hel"},"finish_reason":null}]}"#;
+        let second = r#"{"id":"c1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{"content":"lo"},"finish_reason":null}]}"#;
+        let last = r#"{"id":"c1","object":"chat.completion.chunk","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#;
+        let body = format!("data: {}\r\n\r\ndata: {}\n\n: keep-alive\n\ndata: {}\n\ndata: [DONE]\n\n", first, second, last);
+
+        assert_eq!(
+            reframe(&body),
+            vec![
+                format!("event: content\ndata: {}\n\n", first),
+                format!("event: content\ndata: {}\n\n", second),
+                format!("event: content\ndata: {}\n\nevent: finish\ndata: {{\"finish_reason\":\"stop\"}}\n\n", last),
+                "data: [DONE]\n\n".to_string(),
+            ]
+        );
     }
-    return true;
-  }
 
-  window.addEventListener('keydown', handleKeyboardEvents, { capture: true });
-})();
-"#;
+    fn chat_request(provider: &str) -> ChatRequest {
+        ChatRequest {
+            model: "test-model".to_string(),
+            messages: vec![message("user", "hi")],
+            stream: true,
+            options: None,
+            max_tokens: None,
+            temperature: None,
+            top_p: None,
+            keep_alive: None,
+            provider: Some(provider.to_string()),
+            headers: None,
+            user_agent: None,
+            override_content_type: false,
+        }
+    }
 
-                if let Err(err) = window.eval(script) {
-                    eprintln!("failed to inject reload-block script: {}", err);
+    /// Answers one connection per canned response, in order. Returns the
+    /// base URL and the raw requests received so far.
+    fn mock_upstream(responses: Vec<Vec<u8>>) -> (String, Arc<Mutex<Vec<String>>>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+
+        std::thread::spawn(move || {
+            for response in responses {
+                let Ok((mut conn, _)) = listener.accept() else {
+                    return;
+                };
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = conn.read(&mut buf).unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                    let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                        continue;
+                    };
+                    let head = String::from_utf8_lossy(&request[..end]).to_ascii_lowercase();
+                    let body_len = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .and_then(|len| len.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + body_len {
+                        break;
+                    }
                 }
-                let _ = window.set_decorations(false);
+                seen.lock().unwrap().push(String::from_utf8_lossy(&request).into_owned());
+                let _ = conn.write_all(&response);
             }
+        });
 
-            Ok(())
-        })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        (url, requests)
+    }
+
+    /// Streams `request` from `endpoint` and collects every item, errors
+    /// included.
+    async fn stream_items(endpoint: String, request: ChatRequest, forward_comments: bool) -> Vec<Result<String, String>> {
+        use futures::StreamExt;
+
+        let (_, stream) = proxy_chat_stream(
+            reqwest::Client::new(),
+            &ProviderDefaults::default(),
+            endpoint,
+            None,
+            request,
+            5,
+            forward_comments,
+        )
+        .await
+        .unwrap();
+        stream.map(|item| item.map_err(|e| e.to_string())).collect().await
+    }
+
+    #[actix_web::test]
+    async fn truncated_stream_reports_an_error_instead_of_finishing() {
+        let chunk = r#"data: {"choices":[{"index":0,"delta":{"content":"Hel"},"finish_reason":null}]}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: 4096\r\nConnection: close\r\n\r\n{}\n\n",
+            chunk
+        );
+        let (url, _) = mock_upstream(vec![response.into_bytes()]);
+
+        let items = stream_items(format!("{}/v1/chat/completions", url), chat_request("openai"), false).await;
+
+        assert_eq!(items.len(), 2, "{:?}", items);
+        assert!(items[0].as_ref().unwrap().contains("\"Hel\""));
+        let err = items[1].as_ref().unwrap_err();
+        assert!(err.contains("Upstream connection dropped mid-stream"), "{}", err);
+    }
+
+    fn sampling_request(provider: &str) -> ChatRequest {
+        ChatRequest {
+            max_tokens: Some(256),
+            temperature: Some(0.2),
+            top_p: Some(0.9),
+            options: Some(serde_json::json!({ "top_p": 0.5, "seed": 7 })),
+            ..chat_request(provider)
+        }
+    }
+
+    #[test]
+    fn ollama_body_moves_sampling_settings_into_options() {
+        let body = build_chat_body(&sampling_request("ollama"), Provider::Ollama);
+
+        assert_eq!(
+            body["options"],
+            serde_json::json!({ "num_predict": 256, "temperature": 0.2, "top_p": 0.5, "seed": 7 })
+        );
+        assert!(body.get("max_tokens").is_none());
+        assert!(body.get("temperature").is_none());
+    }
+
+    #[test]
+    fn openai_body_keeps_sampling_settings_top_level() {
+        let body = build_chat_body(&sampling_request("openai"), Provider::OpenAi);
+
+        assert_eq!(body["max_tokens"], 256);
+        assert_eq!(body["temperature"], 0.2);
+        // Same-named keys in `options` win
+        assert_eq!(body["top_p"], 0.5);
+        assert!(body.get("num_predict").is_none());
+    }
+
+    #[test]
+    fn anthropic_stream_events_become_openai_deltas() {
+        let events = [
+            r#"{"type":"message_start","message":{"usage":{"input_tokens":12}}}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hi"}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"max_tokens"},"usage":{"output_tokens":3}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+        let mut input_tokens = 0;
+        let translated: Vec<_> = events
+            .iter()
+            .map(|data| {
+                let event = SseEvent { event: None, id: None, data: data.to_string() };
+                translate_anthropic_event(event, &mut input_tokens)
+            })
+            .collect();
+
+        assert!(translated[1].0.is_none());
+        let text: ChatResponse = serde_json::from_str(&translated[2].0.as_ref().unwrap().data).unwrap();
+        assert_eq!(chunk_content(&text), Some("Hi"));
+        let last: ChatResponse = serde_json::from_str(&translated[3].0.as_ref().unwrap().data).unwrap();
+        assert_eq!(last.finish_reason().as_deref(), Some("length"));
+        assert_eq!(last.usage.as_ref().map(|u| u.total_tokens), Some(15));
+        assert!(translated[4].0.is_none() && translated[4].1);
+    }
+
+    #[actix_web::test]
+    async fn oversized_proxy_body_is_rejected_with_413() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(proxy_json_config(Some(64)))
+                .route("/chat", web::post().to(handle_chat)),
+        )
+        .await;
+
+        let body = serde_json::json!({ "api_endpoint": "http://localhost", "request": chat_request("openai") });
+        let req = actix_web::test::TestRequest::post().uri("/chat").set_json(&body).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), actix_web::http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn stream_cut_off_by_length_reports_it() {
+        let last = r#"{"choices":[{"index":0,"delta":{"content":"…"},"finish_reason":"length"}]}"#;
+        let chunk: ChatResponse = serde_json::from_str(last).unwrap();
+        assert_eq!(chunk.finish_reason().as_deref(), Some("length"));
+
+        let body = format!("data: {}\n\ndata: [DONE]\n\n", last);
+        let out = reframe(&body);
+        assert!(out[0].ends_with("event: finish\ndata: {\"finish_reason\":\"length\"}\n\n"), "{:?}", out);
+    }
+
+    #[actix_web::test]
+    async fn gzip_encoded_chat_response_is_decoded() {
+        use std::io::Write;
+
+        let json = r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"compressed hello"},"finish_reason":"stop"}]}"#;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(json.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            gzipped.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&gzipped);
+        let (url, requests) = mock_upstream(vec![response]);
+
+        let client = build_http_client(SharedProxyRoutes::default(), std::time::Duration::from_secs(5)).unwrap();
+        let mut request = chat_request("openai");
+        request.stream = false;
+        let result = proxy_chat_request(
+            client,
+            &ProviderDefaults::default(),
+            format!("{}/v1/chat/completions", url),
+            None,
+            request,
+            5,
+            0,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result.content, "compressed hello");
+        let sent = requests.lock().unwrap()[0].to_ascii_lowercase();
+        assert!(sent.contains("accept-encoding: gzip"), "{}", sent);
+    }
+
+    #[test]
+    fn tool_call_split_across_three_deltas_is_rebuilt() {
+        let deltas = [
+            r#"[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}]"#,
+            r#"[{"index":0,"function":{"arguments":"{\"city\":"}}]"#,
+            r#"[{"index":0,"function":{"arguments":"\"Paris\"}"}}]"#,
+        ];
+        let mut accumulator = ToolCallAccumulator::default();
+        for delta in deltas {
+            let parsed: Vec<ToolCallDelta> = serde_json::from_str(delta).unwrap();
+            accumulator.push(&parsed);
+        }
+
+        let calls = accumulator.finish();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id.as_deref(), Some("call_1"));
+        assert_eq!(calls[0].kind, "function");
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+    }
+
+    #[test]
+    fn redact_masks_keys_in_formatted_errors() {
+        let error = format!(
+            "error sending request for url (https://generativelanguage.googleapis.com/v1beta/models?key={}&alt=sse): Authorization: Bearer {}",
+            "AIzaSyExampleKey123", "sk-proj-abcdef1234567890"
+        );
+        let redacted = redact(&error);
+        assert!(!redacted.contains("AIzaSyExampleKey123"), "{}", redacted);
+        assert!(!redacted.contains("abcdef1234567890"), "{}", redacted);
+        assert!(redacted.contains("?key=***&alt=sse"), "{}", redacted);
+        assert!(redacted.contains("Bearer ***"), "{}", redacted);
+    }
+
+    #[test]
+    fn concurrent_register_stream_keeps_the_first_token() {
+        let token_map = TokenMap::default();
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| register_stream(&token_map, "same-id")))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let winners: Vec<_> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+        assert_eq!(winners.len(), 1);
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 7);
+
+        // The registered token is the winner's, so cancelling through the map reaches it
+        token_map.lock().unwrap()["same-id"].token.cancel();
+        assert!(winners[0].token.is_cancelled());
+    }
+
+    #[actix_web::test]
+    async fn cors_allows_only_configured_origins() {
+        let origins = vec!["http://localhost:5173".to_string()];
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(build_cors(Some(&origins)))
+                .route("/health", web::get().to(handle_health)),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/health")
+            .insert_header(("Origin", "http://localhost:5173"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("access-control-allow-origin").and_then(|v| v.to_str().ok()),
+            Some("http://localhost:5173")
+        );
+        assert_eq!(
+            resp.headers().get("access-control-allow-credentials").and_then(|v| v.to_str().ok()),
+            Some("true")
+        );
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/health")
+            .insert_header(("Origin", "http://evil.example"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[actix_web::test]
+    async fn multibyte_character_split_across_chunks_is_reassembled() {
+        let line = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"caf\u{e9} \u{1f600}\"},\"finish_reason\":null}]}\n\ndata: [DONE]\n\n";
+        // Split inside the four-byte emoji
+        let split = line.find('\u{1f600}').unwrap() + 2;
+        let (first, second) = line.as_bytes().split_at(split);
+        let mut response =
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        for part in [first, second] {
+            response.extend_from_slice(format!("{:x}\r\n", part.len()).as_bytes());
+            response.extend_from_slice(part);
+            response.extend_from_slice(b"\r\n");
+        }
+        response.extend_from_slice(b"0\r\n\r\n");
+        let (url, _) = mock_upstream(vec![response]);
+
+        let items = stream_items(format!("{}/v1/chat/completions", url), chat_request("openai"), false).await;
+
+        let text: String = items.into_iter().map(Result::unwrap).collect();
+        assert!(text.contains("caf\u{e9} \u{1f600}"), "{}", text);
+        assert!(!text.contains('\u{fffd}'), "{}", text);
+    }
+
+    #[actix_web::test]
+    async fn concurrent_scans_share_one_upstream_request() {
+        let body = r#"{"models":[{"name":"llama3:8b"}]}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        // A second connection would get an error instead of the model list
+        let (url, requests) = mock_upstream(vec![response.into_bytes()]);
+        let tags_url = ollama_api_url(&url, "/api/tags").unwrap();
+        let cache = ScanCache::default();
+        let saves = std::sync::atomic::AtomicUsize::new(0);
+        let scan = || {
+            cached_scan(&tags_url, url.clone(), None, None, None, None, reqwest::Client::new(), &cache, |_| {
+                saves.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            })
+        };
+
+        let (a, b) = tokio::join!(scan(), scan());
+
+        assert_eq!(a.unwrap()[0].name, "llama3:8b");
+        assert_eq!(b.unwrap()[0].name, "llama3:8b");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(saves.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn same_idempotency_key_reaches_upstream_once() {
+        let body = r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"once"},"finish_reason":"stop"}]}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let (url, requests) = mock_upstream(vec![response.into_bytes()]);
+        let cache = IdempotencyCache::default();
+        let send = || -> futures::future::BoxFuture<'static, (Result<ChatResult, ProxyError>, bool)> {
+            let endpoint = format!("{}/v1/chat/completions", url);
+            Box::pin(async move {
+                let mut request = chat_request("openai");
+                request.stream = false;
+                let result =
+                    proxy_chat_request(reqwest::Client::new(), &ProviderDefaults::default(), endpoint, None, request, 5, 0)
+                        .await;
+                (result, true)
+            })
+        };
+
+        let (a, b) = tokio::join!(cache.run("key-1".to_string(), send()), cache.run("key-1".to_string(), send()));
+        let c = cache.run("key-1".to_string(), send()).await;
+
+        for result in [a, b, c] {
+            assert_eq!(result.unwrap().content, "once");
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn ollama_ndjson_stream_becomes_openai_deltas() {
+        let body = concat!(
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"lo\"},\"done\":false}\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"done_reason\":\"stop\",\"prompt_eval_count\":4,\"eval_count\":2}\n",
+        );
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let (url, _) = mock_upstream(vec![response.into_bytes()]);
+
+        let items = stream_items(format!("{}/api/chat", url), chat_request("ollama"), false).await;
+
+        let text: String = items.into_iter().map(Result::unwrap).collect();
+        let chunks: Vec<ChatResponse> = parse_stream_chunks(&text).collect();
+        let content: String = chunks
+            .iter()
+            .filter_map(|c| c.choices.as_ref()?.first()?.delta.as_ref()?.content.clone())
+            .collect();
+        assert_eq!(content, "Hello");
+        assert_eq!(chunks.iter().find_map(ChatResponse::finish_reason).as_deref(), Some("stop"));
+        assert!(text.ends_with("data: [DONE]\n\n"), "{}", text);
+    }
+
+    fn http_response(content_type: &str, body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+
+    const HELLO_CHUNK: &str = r#"data: {"choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":"stop"}]}"#;
+
+    #[actix_web::test]
+    async fn upstream_done_is_forwarded_once() {
+        let body = format!("{}\n\ndata: [DONE]\n\n", HELLO_CHUNK);
+        let (url, _) = mock_upstream(vec![http_response("text/event-stream", &body)]);
+
+        let items = stream_items(format!("{}/v1/chat/completions", url), chat_request("openai"), false).await;
+
+        let text: String = items.into_iter().map(Result::unwrap).collect();
+        assert_eq!(text.matches("data: [DONE]").count(), 1, "{}", text);
+        assert!(text.ends_with("data: [DONE]\n\n"), "{}", text);
+    }
+
+    #[actix_web::test]
+    async fn done_is_added_when_upstream_closes_without_it() {
+        let body = format!("{}\n\n", HELLO_CHUNK);
+        let (url, _) = mock_upstream(vec![http_response("text/event-stream", &body)]);
+
+        let items = stream_items(format!("{}/v1/chat/completions", url), chat_request("openai"), false).await;
+
+        let text: String = items.into_iter().map(Result::unwrap).collect();
+        assert!(text.contains("\"Hello\""), "{}", text);
+        assert_eq!(text.matches("data: [DONE]").count(), 1, "{}", text);
+        assert!(text.ends_with("data: [DONE]\n\n"), "{}", text);
+    }
+
+    #[actix_web::test]
+    async fn dropped_stream_resumes_from_last_event_id() {
+        let first = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: 4096\r\nConnection: close\r\n\r\n{}",
+            "id: evt-1\ndata: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"},\"finish_reason\":null}]}\n\n"
+        );
+        let rest = "id: evt-2\ndata: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"},\"finish_reason\":\"stop\"}]}\n\ndata: [DONE]\n\n";
+        let (url, requests) =
+            mock_upstream(vec![first.into_bytes(), http_response("text/event-stream", rest)]);
+
+        let items = stream_items(format!("{}/v1/chat/completions", url), chat_request("openai"), false).await;
+
+        let text: String = items.into_iter().map(|item| item.unwrap()).collect();
+        assert!(text.contains("\"Hel\"") && text.contains("\"lo\""), "{}", text);
+        assert!(text.ends_with("data: [DONE]\n\n"), "{}", text);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].to_ascii_lowercase().contains("last-event-id: evt-1"), "{}", requests[1]);
+    }
+
+    #[test]
+    fn corrupt_store_is_moved_aside() {
+        let dir = std::env::temp_dir().join(format!("store-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let valid = dir.join("valid.json");
+        let corrupt = dir.join("settings.json");
+        std::fs::write(&valid, r#"{"theme":"dark"}"#).unwrap();
+        std::fs::write(&corrupt, r#"{"theme":"da"#).unwrap();
+
+        assert_eq!(back_up_corrupt_store(&valid), None);
+        assert!(valid.exists());
+        assert_eq!(back_up_corrupt_store(&dir.join("missing.json")), None);
+
+        let backup = back_up_corrupt_store(&corrupt).unwrap();
+        assert!(!corrupt.exists());
+        assert!(backup.file_name().unwrap().to_string_lossy().starts_with("settings.json.corrupt-"));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), r#"{"theme":"da"#);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn comment_lines_are_forwarded_only_when_asked() {
+        let body = format!(": ping\n\n{}\n\ndata: [DONE]\n\n", HELLO_CHUNK);
+
+        let (url, _) = mock_upstream(vec![http_response("text/event-stream", &body)]);
+        let items = stream_items(format!("{}/v1/chat/completions", url), chat_request("openai"), true).await;
+        let items: Vec<String> = items.into_iter().map(Result::unwrap).collect();
+        assert_eq!(items[0], ": ping\n\n");
+        assert!(items[1].contains("\"Hello\""), "{:?}", items);
+
+        let (url, _) = mock_upstream(vec![http_response("text/event-stream", &body)]);
+        let items = stream_items(format!("{}/v1/chat/completions", url), chat_request("openai"), false).await;
+        let text: String = items.into_iter().map(Result::unwrap).collect();
+        assert!(!text.contains("ping"), "{}", text);
+        assert!(text.contains("\"Hello\""), "{}", text);
+    }
+
+    #[test]
+    fn cancel_all_leaves_entries_for_their_owners() {
+        let token_map = TokenMap::default();
+        let ctx = register_stream(&token_map, "busy").unwrap();
+
+        assert_eq!(cancel_all(&token_map).unwrap(), 1);
+        assert!(ctx.token.is_cancelled());
+        // The id stays taken until the owning command has wound down
+        assert!(register_stream(&token_map, "busy").is_err());
+        assert_eq!(cancel_all(&token_map).unwrap(), 0);
+    }
+
+    #[test]
+    fn repair_markdown_only_closes_inline_code_when_cut_off() {
+        assert_eq!(repair_markdown_text("Press the ` key", false), "Press the ` key");
+        assert_eq!(repair_markdown_text("Run `cargo bu", true), "Run `cargo bu`");
+        assert_eq!(repair_markdown_text("```rust\nfn main() {", false), "```rust\nfn main() {\n```");
+        assert_eq!(repair_markdown_text("Done.\n", true), "Done.\n");
+    }
+
+    #[actix_web::test]
+    async fn pull_progress_survives_invalid_utf8() {
+        use futures::StreamExt;
+
+        let mut body = b"{\"status\":\"pulling \xff\xfe manifest\"}\n".to_vec();
+        body.extend_from_slice(b"{\"status\":\"success\"}\n");
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&body);
+        let (url, _) = mock_upstream(vec![response]);
+
+        let stream = proxy_pull_ollama_model(reqwest::Client::new(), url, None, "llama3".to_string()).await.unwrap();
+        let statuses: Vec<String> = stream.map(|progress| progress.unwrap().status).collect().await;
+
+        assert_eq!(statuses, ["pulling \u{fffd}\u{fffd} manifest", "success"]);
+    }
 }
@@ -607,14 +607,16 @@ document.getElementById("export-settings-btn").addEventListener("click", exportS
 
     modelScanStatus.textContent = '正在扫描';
     try {
-      const modelNames = await window.__TAURI__.core.invoke('scan_ollama_models', {
+      const scan = await window.__TAURI__.core.invoke('scan_ollama_models', {
         apiEndpoint,
         apiKey: settings.apiKey || null
       });
+      const models = scan && scan.models;
 
-      if (!Array.isArray(modelNames)) {
+      if (!Array.isArray(models)) {
         throw new Error(`从API返回的格式无效`);
       }
+      const modelNames = models.map(m => m.name);
 
       const currentModels = new Set(settings.customModels || []);
       modelNames.forEach(name => currentModels.add(name));
@@ -627,8 +629,13 @@ document.getElementById("export-settings-btn").addEventListener("click", exportS
         document.getElementById('model').value = settings.customModels[0];
       }
 
-      modelScanStatus.textContent = `扫描完成！找到 ${modelNames.length} 个新模型`;
-      showToast("本地模型扫描成功", "success");
+      if (scan.offline) {
+        modelScanStatus.textContent = `离线：显示上次扫描的 ${modelNames.length} 个模型`;
+        showToast("无法连接API，已使用缓存的模型列表", "info");
+      } else {
+        modelScanStatus.textContent = `扫描完成！找到 ${modelNames.length} 个新模型`;
+        showToast("本地模型扫描成功", "success");
+      }
     } catch (error) {
       console.error("Error scanning Ollama models:", error);
       modelScanStatus.textContent = '扫描失败';
@@ -2494,6 +2501,9 @@ reader.readAsText(file);
               let dataPayload = "";
               for (let line of lines) {
                 const trimmed = line.trim();
+                if (trimmed.startsWith("event:")) {
+                  continue;
+                }
                 if (trimmed.startsWith("data: ")) {
                   dataPayload += trimmed.slice(6);
                 } else if (trimmed !== "data:" && trimmed !== "") {
@@ -2593,12 +2603,12 @@ reader.readAsText(file);
         });
       } else {
         if (window.__TAURI__) {
-          const content = await window.__TAURI__.core.invoke('send_chat_request', {
+          const result = await window.__TAURI__.core.invoke('send_chat_request', {
             apiEndpoint: settings.apiEndpoint,
             apiKey: settings.apiKey || null,
             request: requestData
           });
-          return content;
+          return result.content;
         } else {
           return await getAIResponseFetch(requestSessionId, messageId, onFirstChunk, requestData, continuationCount);
         }
@@ -2874,7 +2884,7 @@ reader.readAsText(file);
 
     try {
       if (window.__TAURI__) {
-        const response = await window.__TAURI__.core.invoke("send_chat_request", {
+        const result = await window.__TAURI__.core.invoke("send_chat_request", {
           apiEndpoint: settings.apiEndpoint,
           apiKey: settings.apiKey || null,
           request: {
@@ -2884,6 +2894,7 @@ reader.readAsText(file);
             options: { temperature: 0.3 }
           }
         });
+        const response = result?.content;
 
         if (response && response.trim()) {
           let newName = response.trim().replace(/^["'""]|["'""]$/g, ""); // Remove quotes if any